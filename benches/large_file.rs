@@ -0,0 +1,108 @@
+//! Measures opening, editing and saving a large synthetic file, e. g. with
+//! `cargo bench -- 2000000` for a file with two million lines (the default is
+//! 500000).
+
+use std::{
+    env,
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        BufWriter,
+        Write,
+    },
+    path::Path,
+    process,
+    time::Instant,
+};
+
+use kiro::{
+    rows::Rows,
+    Editor,
+    KiroResult,
+};
+
+const DEFAULT_LINE_COUNT: usize = 500_000;
+/// Number of times every edit is repeated.
+const EDIT_COUNT: usize = 1000;
+
+fn main() -> KiroResult<()> {
+    let line_count = env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_LINE_COUNT);
+    let path = env::temp_dir().join(format!("kiro-bench-{}.sql", process::id()));
+    let result = run(&path, line_count);
+    fs::remove_file(&path).ok();
+    result
+}
+
+fn run(path: &Path, line_count: usize) -> KiroResult<()> {
+    write_sql_dump(path, line_count)?;
+    let size = fs::metadata(path)?.len();
+    println!(
+        "{} lines, {:.1} MB",
+        line_count,
+        size as f64 / (1024.0 * 1024.0)
+    );
+
+    let mut editor = Editor::default();
+    // Files of this size are loaded in the background after the first lines
+    // were found.
+    measure("open", || editor.open(path))?;
+    measure("finish loading", || editor.finish_loading());
+    measure("save", || editor.save().map(drop))?;
+
+    let text = fs::read_to_string(path)?;
+    let mut rows = Rows::from_text(text, "\n");
+    let middle = line_count / 2;
+    measure_edit("insert line at top", || {
+        rows.insert(0, "INSERT INTO log VALUES (0);".into())
+    });
+    measure_edit("remove line at top", || drop(rows.remove(0)));
+    measure_edit("edit line in middle", || rows.line_mut(middle).push('x'));
+    measure_edit("read line in middle", || assert!(!rows[middle].is_empty()));
+    measure("iterate all lines", || {
+        let bytes: usize = rows.iter().map(str::len).sum();
+        assert!(bytes > 0);
+        KiroResult::Ok(())
+    })?;
+    Ok(())
+}
+
+/// Writes `line_count` lines that look like a dump of a log table.
+fn write_sql_dump(path: &Path, line_count: usize) -> KiroResult<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for id in 0..line_count {
+        writeln!(
+            file,
+            "INSERT INTO log (id, level, message) VALUES ({}, 'info', 'request {} took {} ms');",
+            id,
+            id * 7919 % 100_000,
+            id % 997
+        )?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn measure<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    println!(
+        "{:<24} {:>10.3} ms",
+        name,
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+    result
+}
+
+fn measure_edit(name: &str, mut edit: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..EDIT_COUNT {
+        edit();
+    }
+    let per_edit = start.elapsed().as_secs_f64() * 1e6 / EDIT_COUNT as f64;
+    println!("{:<24} {:>10.3} µs per edit", name, per_edit);
+}
@@ -4,10 +4,36 @@ pub const GOTO_TOP_LEFT: &str = "\x1B[H";
 pub const CLEAR_REST_OF_LINE: &str = "\x1B[0K";
 pub const ALTERNATIVE_BUFFER: &str = "\x1B[?1049h";
 pub const PRIMARY_BUFFER: &str = "\x1B[?1049l";
+pub const ENABLE_BRACKETED_PASTE: &str = "\x1B[?2004h";
+pub const DISABLE_BRACKETED_PASTE: &str = "\x1B[?2004l";
 pub const REVERSE: &str = "\x1B[7m";
+pub const NO_REVERSE: &str = "\x1B[27m";
 pub const RESET: &str = "\x1B[0m";
 pub const EOL: &str = "\r\n";
+/// Asks the terminal to report the contents of the system clipboard.
+pub const QUERY_CLIPBOARD: &str = "\x1B]52;c;?\x07";
 
 pub fn goto_position(x: usize, y: usize) -> String {
     format!("\x1B[{y};{x}H", x = x, y = y)
 }
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Color {
+    Red = 31,
+    Green = 32,
+    Yellow = 33,
+    Blue = 34,
+    Magenta = 35,
+    Cyan = 36,
+    Default = 39,
+}
+
+pub fn foreground(color: Color) -> String {
+    format!("\x1B[{}m", color as u8)
+}
+
+/// Sets the system clipboard to `contents` using OSC 52. Terminals that do not
+/// support it ignore the sequence.
+pub fn set_clipboard(contents: &[u8]) -> String {
+    format!("\x1B]52;c;{}\x07", crate::base64::encode(contents))
+}
@@ -0,0 +1,76 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes `bytes` as padded base64.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0_u32, |group, (i, &byte)| {
+            group | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize].into());
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes padded or unpadded base64, returning `None` if `s` contains
+/// anything else.
+pub(crate) fn decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(s.len() * 3 / 4);
+    let mut group = 0_u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)?;
+        group = group << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((group >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_with_padding() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"abc"), "YWJj");
+        assert_eq!(encode(b"abcd"), "YWJjZA==");
+        assert_eq!(encode(b"abcde"), "YWJjZGU=");
+        assert_eq!(encode(&[0xFF, 0xEF, 0xBE]), "/+++");
+    }
+
+    #[test]
+    fn decodes_what_was_encoded() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        // Lengths that need 0, 1 and 2 padding characters.
+        for len in 0..bytes.len() {
+            let encoded = encode(&bytes[..len]);
+            assert_eq!(encoded.len() % 4, 0);
+            assert_eq!(encoded.matches('=').count(), (3 - len % 3) % 3);
+            assert_eq!(decode(&encoded).unwrap(), &bytes[..len]);
+            assert_eq!(
+                decode(encoded.trim_end_matches('=')).unwrap(),
+                &bytes[..len]
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        for s in &["YW Jj", "YWJj\n", "YW=Jj", "YWJ-", "YWJj_", "ä"] {
+            assert_eq!(decode(s), None, "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::VecDeque,
+    io::{
+        self,
+        Write,
+    },
+    ops::Range,
+    str::FromStr,
+};
+
+use crate::{
+    ansi,
+    history::{
+        Change,
+        EditKind,
+    },
+    raw_bytes,
+    Editor,
+};
+
+/// How many copied or cut texts are remembered.
+const KILL_RING_SIZE: usize = 16;
+
+/// Texts larger than this are not sent to the system clipboard. Their base64
+/// encoding is about 100 kB, which is the most some terminals accept.
+const SYSTEM_CLIPBOARD_LIMIT: usize = 74_994;
+
+/// How the system clipboard is accessed through the terminal (using OSC 52).
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum SystemClipboard {
+    /// Only use the internal kill ring.
+    Disabled,
+    /// Copied and cut texts are also put into the system clipboard.
+    #[default]
+    Copy,
+    /// Additionally, pasting asks the terminal for the contents of the system
+    /// clipboard. Most terminals do not allow this by default.
+    CopyAndPaste,
+}
+
+impl FromStr for SystemClipboard {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SystemClipboard, ()> {
+        match s {
+            "off" => Ok(SystemClipboard::Disabled),
+            "copy" => Ok(SystemClipboard::Copy),
+            "paste" => Ok(SystemClipboard::CopyAndPaste),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns the escape sequence that puts `text` into the system clipboard, or
+/// its length in bytes if it is too long.
+fn set_system_clipboard(text: &str) -> Result<String, usize> {
+    // Raw bytes are put into the clipboard as they were in the file.
+    let contents = raw_bytes::encode(text);
+    if contents.len() <= SYSTEM_CLIPBOARD_LIMIT {
+        Ok(ansi::set_clipboard(&contents))
+    }
+    else {
+        Err(contents.len())
+    }
+}
+
+/// The anchor of the selection. The selection extends from here to the
+/// cursor.
+#[derive(Clone, Copy)]
+pub(crate) struct Mark {
+    pub(crate) row: usize,
+    col: usize,
+    /// Whether the mark was set by moving the cursor with Shift held, in which
+    /// case moving without Shift deselects.
+    by_shift: bool,
+}
+
+/// Texts that were copied or cut, most recent first.
+#[derive(Default)]
+pub(crate) struct KillRing {
+    entries: VecDeque<String>,
+    /// The entry that was pasted last and where, so that it can be replaced
+    /// by an older one.
+    last_paste: Option<(usize, (usize, usize))>,
+}
+
+impl KillRing {
+    fn push(&mut self, text: String) {
+        self.entries.push_front(text);
+        self.entries.truncate(KILL_RING_SIZE);
+    }
+}
+
+impl Editor {
+    /// Sets the mark at the cursor, or removes it if it is already set.
+    pub(crate) fn toggle_mark(&mut self) {
+        if self.mark.take().is_some() {
+            self.set_status("Mark deactivated".into());
+        }
+        else {
+            self.set_mark(false);
+            self.set_status("Mark set".into());
+        }
+    }
+
+    /// Starts selecting with Shift held unless there already is a selection.
+    pub(crate) fn start_shift_selection(&mut self) {
+        if self.mark.is_none() {
+            self.set_mark(true);
+        }
+    }
+
+    /// Ends a selection that was made with Shift held.
+    pub(crate) fn end_shift_selection(&mut self) {
+        if self.mark.map_or(false, |mark| mark.by_shift) {
+            self.clear_mark();
+        }
+    }
+
+    pub(crate) fn clear_mark(&mut self) {
+        self.mark = None;
+    }
+
+    fn set_mark(&mut self, by_shift: bool) {
+        self.mark = Some(Mark { row: self.cy, col: self.cx, by_shift });
+    }
+
+    /// Returns the start and end of the selection as `(row, col)` pairs, or
+    /// `None` if nothing is selected.
+    fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let mark = self.mark?;
+        let clamp = |(row, col): (usize, usize)| match self.rows.get(row) {
+            Some(_) => (row, col),
+            None => self
+                .rows
+                .last()
+                .map_or((0, 0), |last| (self.rows.len() - 1, last.len())),
+        };
+        let (start, end) = {
+            let mark = clamp((mark.row, mark.col));
+            let cursor = clamp((self.cy, self.cx));
+            (mark.min(cursor), mark.max(cursor))
+        };
+        if start == end {
+            None
+        }
+        else {
+            Some((start, end))
+        }
+    }
+
+    /// Returns the byte range of row `filerow` that is selected, if any.
+    pub(crate) fn selection_in_row(&self, filerow: usize) -> Option<Range<usize>> {
+        let ((start_row, start_col), (end_row, end_col)) = self.selection()?;
+        if !(start_row..=end_row).contains(&filerow) {
+            return None;
+        }
+        let start = if filerow == start_row { start_col } else { 0 };
+        let end = if filerow == end_row {
+            end_col
+        }
+        else {
+            usize::MAX
+        };
+        Some(start..end)
+    }
+
+    fn selected_text(&self) -> Option<String> {
+        let ((start_row, start_col), (end_row, end_col)) = self.selection()?;
+        if start_row == end_row {
+            return Some(self.rows[start_row][start_col..end_col].to_owned());
+        }
+        let mut text = self.rows[start_row][start_col..].to_owned();
+        for row in self.rows.lines(start_row + 1..end_row) {
+            text.push('\n');
+            text.push_str(row);
+        }
+        text.push('\n');
+        text.push_str(&self.rows[end_row][..end_col]);
+        Some(text)
+    }
+
+    pub fn set_system_clipboard(&mut self, system_clipboard: SystemClipboard) {
+        self.system_clipboard = system_clipboard;
+    }
+
+    pub(crate) fn copy(&mut self) {
+        match self.selected_text() {
+            Some(text) => {
+                self.set_status(format!("Copied {} bytes", text.len()));
+                self.kill(text);
+                self.clear_mark();
+            }
+            None => self.set_status("Nothing selected".into()),
+        }
+    }
+
+    pub(crate) fn cut(&mut self) {
+        let ((row, col), text) = match (self.selection(), self.selected_text()) {
+            (Some((start, _)), Some(text)) => (start, text),
+            _ => {
+                self.set_status("Nothing selected".into());
+                return;
+            }
+        };
+        let cursor_before = self.cursor();
+        self.apply_change(Change::Delete { row, col, text: text.clone() });
+        self.set_cursor(row, col);
+        self.commit_edit(EditKind::Other, cursor_before);
+        self.kill(text);
+    }
+
+    /// Puts `text` into the kill ring and the system clipboard.
+    fn kill(&mut self, text: String) {
+        if self.system_clipboard != SystemClipboard::Disabled {
+            match set_system_clipboard(&text) {
+                Ok(sequence) => {
+                    print!("{}", sequence);
+                    // Updating the system clipboard is best effort.
+                    io::stdout().flush().ok();
+                }
+                Err(len) => self.set_status(format!(
+                    "{} bytes are too many for the system clipboard",
+                    len
+                )),
+            }
+        }
+        self.kill_ring.push(text);
+    }
+
+    /// Inserts the most recently copied or cut text at the cursor. If enabled,
+    /// the terminal is asked for the system clipboard instead and the text is
+    /// pasted once it answers.
+    pub(crate) fn paste(&mut self) {
+        if self.system_clipboard == SystemClipboard::CopyAndPaste {
+            print!("{}", ansi::QUERY_CLIPBOARD);
+            io::stdout().flush().ok();
+        }
+        else {
+            self.paste_entry(0);
+        }
+    }
+
+    /// Pastes `text` that the terminal reported as the contents of the system
+    /// clipboard.
+    pub(crate) fn paste_from_system_clipboard(&mut self, text: String) {
+        if self.kill_ring.entries.front() != Some(&text) {
+            self.kill_ring.push(text);
+        }
+        self.paste_entry(0);
+    }
+
+    /// Replaces the text that was just pasted with the next older entry of
+    /// the kill ring.
+    pub(crate) fn paste_older(&mut self) {
+        let (index, (row, col)) = match self.kill_ring.last_paste {
+            Some(last_paste) => last_paste,
+            None => {
+                self.set_status("Previous command was not a paste".into());
+                return;
+            }
+        };
+        let cursor_before = self.cursor();
+        let text = self.kill_ring.entries[index].clone();
+        self.apply_change(Change::Delete { row, col, text });
+        self.set_cursor(row, col);
+        self.commit_edit(EditKind::Other, cursor_before);
+        self.paste_entry((index + 1) % self.kill_ring.entries.len());
+    }
+
+    /// Forgets where the last paste happened, which must be done whenever
+    /// something else is done in between.
+    pub(crate) fn finish_paste(&mut self) {
+        self.kill_ring.last_paste = None;
+    }
+
+    /// Inserts text that was pasted into the terminal as one undoable edit.
+    pub(crate) fn insert_pasted_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let cursor_before = self.cursor();
+        self.insert_text(text);
+        self.commit_edit(EditKind::Other, cursor_before);
+    }
+
+    fn paste_entry(&mut self, index: usize) {
+        let text = match self.kill_ring.entries.get(index) {
+            Some(text) => text.clone(),
+            None => {
+                self.set_status("Nothing to paste".into());
+                return;
+            }
+        };
+        let start = (self.cy, self.cx);
+        self.insert_pasted_text(&text);
+        self.kill_ring.last_paste = Some((index, start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base64;
+
+    #[test]
+    fn long_texts_are_not_sent_to_the_system_clipboard() {
+        let text = "x".repeat(SYSTEM_CLIPBOARD_LIMIT);
+        let sequence = set_system_clipboard(&text).unwrap();
+        let encoded = sequence
+            .strip_prefix("\x1B]52;c;")
+            .and_then(|rest| rest.strip_suffix('\x07'))
+            .unwrap();
+        assert_eq!(encoded.len(), 99_992);
+        assert_eq!(base64::decode(encoded).unwrap(), text.as_bytes());
+
+        let text = "x".repeat(SYSTEM_CLIPBOARD_LIMIT + 1);
+        assert_eq!(set_system_clipboard(&text), Err(SYSTEM_CLIPBOARD_LIMIT + 1));
+        // The limit is on the bytes in the file, not on the decoded text.
+        let text = raw_bytes::decode(vec![0xFF; SYSTEM_CLIPBOARD_LIMIT + 1]);
+        assert_eq!(set_system_clipboard(&text), Err(SYSTEM_CLIPBOARD_LIMIT + 1));
+    }
+
+    #[test]
+    fn long_texts_are_still_copied_to_the_kill_ring() {
+        let mut editor = Editor::default();
+        editor.kill("x".repeat(SYSTEM_CLIPBOARD_LIMIT + 1));
+        assert_eq!(
+            editor.status.message,
+            "74995 bytes are too many for the system clipboard"
+        );
+        assert_eq!(editor.kill_ring.entries.front().unwrap().len(), 74_995);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    env,
+    fs::{
+        self,
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        BufWriter,
+        Write,
+    },
+    os::unix::fs::{
+        MetadataExt,
+        OpenOptionsExt,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        self,
+        Command,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::{
+    event_loop::Timer,
+    input::{
+        Key,
+        KeyEvent,
+    },
+    prompt::{
+        Hooks,
+        PromptKind,
+    },
+    terminal,
+    unicode,
+    Editor,
+};
+
+/// How often the file is checked for changes by other programs.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shows the differences between the file `$1` and its version `$2`, which is
+/// described by `$3`.
+const DIFF_COMMAND: &str =
+    r#"diff -u -L "$1 (on disk)" -L "$1 ($3)" -- "$1" "$2" | ${PAGER:-less}"#;
+
+/// Identifies a version of a file on disk. If any of this changes, the file
+/// was modified or replaced.
+#[derive(Clone, Copy, PartialEq)]
+struct FileStamp {
+    device: u64,
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileStamp {
+    /// Returns the stamp of the file at `path`, or `None` if it does not exist
+    /// (or cannot be accessed).
+    fn of(path: &Path) -> Option<FileStamp> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(FileStamp {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+}
+
+/// What is known about the file on disk.
+#[derive(Default)]
+pub(crate) struct DiskState {
+    /// The file as it was when it was last read or written.
+    stamp: Option<FileStamp>,
+    /// Whether the user was told that the file was changed by another program.
+    pub(crate) changed: bool,
+}
+
+impl Editor {
+    /// Remembers the current state of the file, which must be done whenever
+    /// the buffer is read from or written to it.
+    pub(crate) fn remember_disk_state(&mut self) {
+        let stamp = self.filename.as_deref().and_then(FileStamp::of);
+        self.disk = DiskState { stamp, changed: false };
+        if self.filename.is_some() {
+            self.timers
+                .set(Timer::DiskCheck, Instant::now() + CHECK_INTERVAL);
+        }
+    }
+
+    /// Tells whether another program changed the file since it was last read
+    /// or written. A deleted file does not count, saving just recreates it.
+    fn changed_on_disk(&self) -> bool {
+        let stamp = self.filename.as_deref().and_then(FileStamp::of);
+        stamp.is_some() && stamp != self.disk.stamp
+    }
+
+    /// Checks the file for changes when `Timer::DiskCheck` expired and warns
+    /// the user about them. Returns whether the screen needs to be redrawn.
+    pub(crate) fn check_disk(&mut self) -> bool {
+        if !self.changed_on_disk() {
+            self.timers
+                .set(Timer::DiskCheck, Instant::now() + CHECK_INTERVAL);
+            return false;
+        }
+        let path = self.filename.as_deref().unwrap();
+        if self
+            .rows
+            .mapping()
+            .map_or(false, |mapping| mapping.is_shrunk_at(path))
+        {
+            // The lines past the new end of the file must not be read anymore.
+            self.drop_truncated_mapping();
+            return true;
+        }
+        self.disk.changed = true;
+        self.set_status(format!(
+            "`{}` was changed by another program. Press Ctrl-R to reload, overwrite or diff it",
+            self.filename()
+        ));
+        true
+    }
+
+    /// Saves the buffer, unless another program changed the file in the
+    /// meantime, in which case the user is asked what to do.
+    pub(crate) fn save_unless_changed_on_disk(&mut self) {
+        if self.changed_on_disk() {
+            self.start_resolve_disk_change();
+        }
+        else {
+            self.save_and_report();
+        }
+    }
+
+    /// Asks whether to reload the file, overwrite it with the buffer or show
+    /// the differences between both.
+    pub(crate) fn start_resolve_disk_change(&mut self) {
+        if self.filename.is_none() {
+            self.set_status("No file to reload".into());
+            return;
+        }
+        let hooks = Hooks {
+            on_key: |editor, key| {
+                match key {
+                    KeyEvent { key: Key::Esc, .. } => return false,
+                    KeyEvent { key: Key::Char('r'), .. } => {
+                        editor.close_prompt();
+                        editor.reload();
+                    }
+                    KeyEvent { key: Key::Char('o'), .. } => {
+                        editor.close_prompt();
+                        editor.save_and_report();
+                    }
+                    KeyEvent { key: Key::Char('d'), .. } =>
+                        if let Err(err) = editor.show_buffer_diff() {
+                            editor.close_prompt();
+                            editor.set_status(format!("Could not show the diff: {:?}", err));
+                        },
+                    _ => (),
+                }
+                true
+            },
+            ..Hooks::default()
+        };
+        let message = if self.changed_on_disk() {
+            "File changed on disk"
+        }
+        else {
+            "File on disk"
+        };
+        self.start_prompt(
+            PromptKind::DiskChange,
+            message,
+            "(r = reload, o = overwrite, d = diff, ESC to cancel)",
+            String::new(),
+            hooks,
+        );
+    }
+
+    /// Replaces the buffer with the file on disk, keeping the cursor on the
+    /// same line.
+    pub(crate) fn reload(&mut self) {
+        let (cy, rx) = (self.cy, self.rx());
+        if let Err(err) = self.load() {
+            self.set_status(format!("Could not reload `{}`: {:?}", self.filename(), err));
+            return;
+        }
+        let cy = cy.min(self.rows.len().saturating_sub(1));
+        let cx = self
+            .rows
+            .get(cy)
+            .map_or(0, |row| unicode::offset_at_column(row, rx, self.tab_width));
+        self.set_cursor(cy, cx);
+        self.remove_recovery_file();
+        self.set_status(format!("Reloaded `{}`", self.filename()));
+    }
+
+    /// Shows the differences between the file on disk and the buffer in a
+    /// pager.
+    fn show_buffer_diff(&mut self) -> io::Result<()> {
+        self.wait_for_loading();
+        let path = self.filename.clone().unwrap();
+        let (buffer_path, file) = create_temp_file(&env::temp_dir())?;
+        let result = self
+            .write_buffer_to(file)
+            .and_then(|()| show_diff(&path, &buffer_path, "buffer"));
+        fs::remove_file(&buffer_path).ok();
+        result
+    }
+
+    fn write_buffer_to(&self, file: File) -> io::Result<()> {
+        let mut writer = BufWriter::new(file);
+        self.file_format.write(&self.rows, &mut writer)?;
+        writer.flush()
+    }
+}
+
+/// Creates a new file in `dir` that only the user can read, as it holds the
+/// contents of the buffer. Files that already exist (or symlinks that someone
+/// else put there) are never opened, another name is used instead.
+fn create_temp_file(dir: &Path) -> io::Result<(PathBuf, File)> {
+    for count in 0..100 {
+        let path = dir.join(format!("kiro-{}-{}.diff", process::id(), count));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path);
+        match file {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Could not find an unused name for a temporary file",
+    ))
+}
+
+/// Shows the differences between the file at `path` and `version` of it in a
+/// pager.
+pub(crate) fn show_diff(path: &Path, version: &Path, description: &str) -> io::Result<()> {
+    run_in_terminal(
+        Command::new("sh")
+            .arg("-c")
+            .arg(DIFF_COMMAND)
+            .arg("sh")
+            .arg(path)
+            .arg(version)
+            .arg(description),
+    )
+}
+
+/// Runs `command` with the terminal in the state the editor was started in.
+fn run_in_terminal(command: &mut Command) -> io::Result<()> {
+    terminal::suspend()?;
+    let result = command.status();
+    terminal::resume()?;
+    result.map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{
+        symlink,
+        PermissionsExt,
+    };
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn temp_files_are_new_and_private() {
+        let dir = TempDir::new();
+        let name = |count| format!("kiro-{}-{}.diff", process::id(), count);
+        fs::write(dir.path().join(name(0)), "stale").unwrap();
+        symlink("target", dir.path().join(name(1))).unwrap();
+
+        let (path, mut file) = create_temp_file(dir.path()).unwrap();
+        assert_eq!(path, dir.path().join(name(2)));
+        file.write_all(b"buffer").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"buffer");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert_eq!(fs::read(dir.path().join(name(0))).unwrap(), b"stale");
+        assert!(!dir.path().join("target").exists());
+        let (path, _) = create_temp_file(dir.path()).unwrap();
+        assert_eq!(path, dir.path().join(name(3)));
+    }
+}
@@ -0,0 +1,197 @@
+//! The main loop of the editor: it sleeps in `poll` until there is input from
+//! the terminal, a signal arrived or one of the timers expired.
+
+use std::{
+    convert::TryFrom,
+    io,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use libc::{
+    c_int,
+    pollfd,
+};
+
+use crate::{
+    input,
+    terminal,
+    Editor,
+    Error,
+    KiroResult,
+};
+
+/// Something that happens at a certain time rather than in response to
+/// input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Timer {
+    /// The status message timed out and has to be removed from the screen.
+    StatusMessage,
+    /// Unsaved changes are written to the recovery file.
+    RecoveryFile,
+    /// The file is checked for changes by other programs.
+    DiskCheck,
+}
+
+/// The deadlines of the timers that are currently set.
+#[derive(Default)]
+pub(crate) struct Timers {
+    deadlines: Vec<(Timer, Instant)>,
+}
+
+impl Timers {
+    /// Makes `timer` expire at `deadline`, replacing its previous deadline.
+    pub(crate) fn set(&mut self, timer: Timer, deadline: Instant) {
+        self.cancel(timer);
+        self.deadlines.push((timer, deadline));
+    }
+
+    pub(crate) fn cancel(&mut self, timer: Timer) {
+        self.deadlines.retain(|&(set, _)| set != timer);
+    }
+
+    pub(crate) fn is_set(&self, timer: Timer) -> bool {
+        self.deadlines.iter().any(|&(set, _)| set == timer)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.iter().map(|&(_, deadline)| deadline).min()
+    }
+
+    /// Removes the timers that expired by `now` and returns them.
+    fn expire(&mut self, now: Instant) -> Vec<Timer> {
+        let (expired, pending): (Vec<_>, Vec<_>) = self
+            .deadlines
+            .drain(..)
+            .partition(|&(_, deadline)| deadline <= now);
+        self.deadlines = pending;
+        expired.into_iter().map(|(timer, _)| timer).collect()
+    }
+}
+
+impl Editor {
+    /// Reads and processes input from `fd` until the user quits.
+    pub fn run(&mut self, fd: c_int) -> KiroResult<()> {
+        self.update_window_size(fd)?;
+        let mut events = input::EventReader::new(fd);
+        let mut redraw = true;
+        loop {
+            for signal in terminal::take_signals()? {
+                match signal {
+                    libc::SIGWINCH => {
+                        // If the new size cannot be determined, the old one is
+                        // still better than giving up.
+                        self.update_window_size(fd).ok();
+                    }
+                    libc::SIGBUS => self.mapped_file_truncated(),
+                    _ => {
+                        self.save_for_recovery();
+                        return Err(Error::Signal(signal));
+                    }
+                }
+                redraw = true;
+            }
+            let received_lines = self.receive_lines();
+            redraw |= received_lines;
+            for timer in self.timers.expire(Instant::now()) {
+                redraw |= self.timer_expired(timer);
+            }
+            if redraw {
+                self.draw()?;
+                redraw = false;
+            }
+
+            // More lines may be waiting already, their wake-up was consumed
+            // with the one of the lines just received.
+            let deadline = if received_lines {
+                Some(Instant::now())
+            }
+            else {
+                self.timers.next_deadline()
+            };
+            match wait(fd, deadline) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    self.save_for_recovery();
+                    return Err(err.into());
+                }
+            }
+            // Everything that is available is processed before the screen is
+            // drawn again, so that drawing does not lag behind fast input.
+            while let Some(event) = events.read_event()? {
+                if !self.process_event(event) {
+                    self.remove_recovery_file();
+                    return Ok(());
+                }
+                redraw = true;
+            }
+        }
+    }
+
+    /// Does what is due when `timer` expired. Returns whether the screen
+    /// needs to be redrawn.
+    fn timer_expired(&mut self, timer: Timer) -> bool {
+        match timer {
+            Timer::StatusMessage => true,
+            Timer::RecoveryFile => self.update_recovery_file(),
+            Timer::DiskCheck => self.check_disk(),
+        }
+    }
+}
+
+/// Sleeps until there is input on `fd`, a signal arrived or `deadline`
+/// passed. Returns whether there is input.
+fn wait(fd: c_int, deadline: Option<Instant>) -> io::Result<bool> {
+    let mut fds = [
+        pollfd { fd, events: libc::POLLIN, revents: 0 },
+        pollfd {
+            fd: terminal::signal_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    poll(&mut fds, timeout)?;
+    let revents = fds[0].revents;
+    if revents & libc::POLLIN == 0 && revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The terminal was closed",
+        ));
+    }
+    Ok(revents & libc::POLLIN != 0)
+}
+
+/// Waits up to `timeout` for input on `fd`. Returns whether there is some.
+pub(crate) fn wait_for_input(fd: c_int, timeout: Duration) -> io::Result<bool> {
+    let mut fds = [pollfd { fd, events: libc::POLLIN, revents: 0 }];
+    poll(&mut fds, Some(timeout))?;
+    Ok(fds[0].revents != 0)
+}
+
+/// Waits up to `timeout` (or forever if it is `None`) until one of `fds` is
+/// ready. Being interrupted by a signal counts as a timeout, the signal is
+/// picked up from its pipe afterwards.
+fn poll(fds: &mut [pollfd], timeout: Option<Duration>) -> io::Result<()> {
+    // Rounded up, so that the deadline has passed when `poll` returns.
+    let timeout = timeout.map_or(-1, |timeout| {
+        let millis = (timeout.as_nanos() + 999_999) / 1_000_000;
+        c_int::try_from(millis).unwrap_or(c_int::MAX)
+    });
+    match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                fds.iter_mut().for_each(|fd| fd.revents = 0);
+                Ok(())
+            }
+            else {
+                Err(err)
+            }
+        }
+        _ => Ok(()),
+    }
+}
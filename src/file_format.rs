@@ -0,0 +1,212 @@
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+
+use crate::{
+    raw_bytes,
+    Editor,
+    Rows,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+/// How the rows of a buffer are separated in its file.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct FileFormat {
+    line_ending: LineEnding,
+    /// Whether the last row is terminated by a line ending, too.
+    final_newline: bool,
+}
+
+impl Default for FileFormat {
+    fn default() -> FileFormat {
+        FileFormat {
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+        }
+    }
+}
+
+impl FileFormat {
+    pub(crate) fn new(line_ending: LineEnding, final_newline: bool) -> FileFormat {
+        FileFormat { line_ending, final_newline }
+    }
+
+    /// Splits the contents of `reader` into rows and detects their format.
+    /// Rows are only considered CRLF terminated if all of them are, so that
+    /// files with mixed line endings are saved unchanged (with the `\r`s as
+    /// part of the rows).
+    pub(crate) fn read(mut reader: impl Read) -> io::Result<(Rows, FileFormat)> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let text = raw_bytes::decode(bytes);
+
+        let (mut lf_count, mut crlf_count) = (0, 0);
+        for (idx, _) in text.match_indices('\n') {
+            if text[..idx].ends_with('\r') {
+                crlf_count += 1;
+            }
+            else {
+                lf_count += 1;
+            }
+        }
+        let line_ending = if crlf_count > 0 && lf_count == 0 {
+            LineEnding::CrLf
+        }
+        else {
+            LineEnding::Lf
+        };
+        let final_newline = text.is_empty() || text.ends_with('\n');
+        let rows = Rows::from_text(text, line_ending.as_str());
+        Ok((rows, FileFormat { line_ending, final_newline }))
+    }
+
+    /// Writes `rows` to `writer` in this format.
+    pub(crate) fn write(self, rows: &Rows, writer: &mut dyn Write) -> io::Result<()> {
+        rows.write(writer, self.line_ending.as_str(), self.final_newline)
+    }
+
+    /// Describes the format for the status bar.
+    pub(crate) fn name(self) -> String {
+        if self.final_newline {
+            self.line_ending.name().into()
+        }
+        else {
+            format!("{} noeol", self.line_ending.name())
+        }
+    }
+}
+
+impl Editor {
+    /// Switches the line endings used when saving between LF and CRLF.
+    pub(crate) fn toggle_line_ending(&mut self) {
+        self.wait_for_loading();
+        self.file_format.line_ending = match self.file_format.line_ending {
+            LineEnding::Lf => LineEnding::CrLf,
+            LineEnding::CrLf => LineEnding::Lf,
+        };
+        self.buffer_changed();
+        self.set_status(format!(
+            "Line endings will be saved as {}",
+            self.file_format.line_ending.name()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        input::{
+            Key,
+            KeyEvent,
+            Modifiers,
+        },
+        test_util::TempDir,
+    };
+
+    /// Reads `bytes` and writes them back, returning the rows, the detected
+    /// format and the written bytes.
+    fn round_trip(bytes: &[u8]) -> (Vec<String>, String, Vec<u8>) {
+        let (rows, file_format) = FileFormat::read(bytes).unwrap();
+        let mut written = Vec::new();
+        file_format.write(&rows, &mut written).unwrap();
+        let lines = rows.iter().map(str::to_owned).collect();
+        (lines, file_format.name(), written)
+    }
+
+    #[test]
+    fn line_endings_are_detected() {
+        let (lines, name, written) = round_trip(b"one\ntwo\n");
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(name, "LF");
+        assert_eq!(written, b"one\ntwo\n");
+
+        let (lines, name, written) = round_trip(b"one\r\ntwo\r\n");
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(name, "CRLF");
+        assert_eq!(written, b"one\r\ntwo\r\n");
+
+        let (lines, name, written) = round_trip(b"");
+        assert!(lines.is_empty());
+        assert_eq!(name, "LF");
+        assert_eq!(written, b"");
+    }
+
+    #[test]
+    fn mixed_line_endings_keep_their_carriage_returns() {
+        let (lines, name, written) = round_trip(b"one\r\ntwo\nthree\r\n");
+        assert_eq!(lines, ["one\r", "two", "three\r"]);
+        assert_eq!(name, "LF");
+        assert_eq!(written, b"one\r\ntwo\nthree\r\n");
+
+        // A lone `\r` does not end a line.
+        let (lines, name, written) = round_trip(b"one\rtwo\r\n");
+        assert_eq!(lines, ["one\rtwo"]);
+        assert_eq!(name, "CRLF");
+        assert_eq!(written, b"one\rtwo\r\n");
+    }
+
+    #[test]
+    fn missing_final_newline_is_kept() {
+        let (lines, name, written) = round_trip(b"one\ntwo");
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(name, "LF noeol");
+        assert_eq!(written, b"one\ntwo");
+
+        let (lines, name, written) = round_trip(b"one\r\ntwo");
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(name, "CRLF noeol");
+        assert_eq!(written, b"one\r\ntwo");
+
+        let (lines, name, written) = round_trip(b"one\r");
+        assert_eq!(lines, ["one\r"]);
+        assert_eq!(name, "LF noeol");
+        assert_eq!(written, b"one\r");
+    }
+
+    #[test]
+    fn alt_l_switches_the_saved_line_endings() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        fs::write(&path, "one\r\ntwo").unwrap();
+        let mut editor = Editor::default();
+        editor.open(&path).unwrap();
+        let alt_l = KeyEvent::new(Key::Char('l'), Modifiers::ALT);
+
+        assert!(editor.process_keypress(alt_l));
+        assert!(editor.dirty);
+        assert_eq!(editor.file_format.name(), "LF noeol");
+        editor.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"one\ntwo");
+
+        assert!(editor.process_keypress(alt_l));
+        assert_eq!(editor.file_format.name(), "CRLF noeol");
+        editor.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"one\r\ntwo");
+    }
+}
@@ -0,0 +1,173 @@
+use std::mem;
+
+use crate::{
+    Cursor,
+    Rows,
+};
+
+/// A primitive modification of the buffer. Every edit is made up of these,
+/// so that undoing it is just applying the inverse changes in reverse order.
+#[derive(Clone, Debug)]
+pub(crate) enum Change {
+    /// Inserts `text` (which may contain newlines) at `row`/`col`.
+    Insert {
+        row: usize,
+        col: usize,
+        text: String,
+    },
+    /// Deletes `text` (which may contain newlines) at `row`/`col`.
+    Delete {
+        row: usize,
+        col: usize,
+        text: String,
+    },
+    /// Inserts a new row `line` before row `row`.
+    InsertLine { row: usize, line: String },
+    /// Removes row `row`, which contains `line`.
+    RemoveLine { row: usize, line: String },
+}
+
+impl Change {
+    pub(crate) fn apply(&self, rows: &mut Rows) {
+        match self {
+            Change::Insert { row, col, text } => {
+                let tail = rows.line_mut(*row).split_off(*col);
+                let mut lines = text.split('\n');
+                rows.line_mut(*row).push_str(lines.next().unwrap_or(""));
+                let lines: Vec<_> = lines.map(str::to_owned).collect();
+                let last_row = row + lines.len();
+                rows.insert_lines(row + 1, lines);
+                rows.line_mut(last_row).push_str(&tail);
+            }
+            Change::Delete { row, col, text } => {
+                let line_count = text.matches('\n').count();
+                let end_row = row + line_count;
+                let end_col = match text.rfind('\n') {
+                    Some(idx) => text.len() - idx - 1,
+                    None => col + text.len(),
+                };
+                let tail = rows.line_mut(end_row).split_off(end_col);
+                rows.remove_lines(row + 1..end_row + 1);
+                let line = rows.line_mut(*row);
+                line.truncate(*col);
+                line.push_str(&tail);
+            }
+            Change::InsertLine { row, line } => rows.insert(*row, line.clone()),
+            Change::RemoveLine { row, .. } => {
+                rows.remove(*row);
+            }
+        }
+    }
+
+    fn inverse(&self) -> Change {
+        match self.clone() {
+            Change::Insert { row, col, text } => Change::Delete { row, col, text },
+            Change::Delete { row, col, text } => Change::Insert { row, col, text },
+            Change::InsertLine { row, line } => Change::RemoveLine { row, line },
+            Change::RemoveLine { row, line } => Change::InsertLine { row, line },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum EditKind {
+    /// A typed character. Runs of these are undone in one step.
+    Typing,
+    Other,
+}
+
+/// One undoable step, consisting of all changes made by a single user action
+/// (or a run of typed characters).
+struct Step {
+    changes: Vec<Change>,
+    kind: EditKind,
+    cursor_before: Cursor,
+    cursor_after: Cursor,
+}
+
+pub(crate) struct History {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    pending: Vec<Change>,
+    /// Length of `undo` when the buffer was last saved, or `None` if that
+    /// state cannot be reached anymore.
+    saved: Option<usize>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            pending: Vec::new(),
+            saved: Some(0),
+        }
+    }
+}
+
+impl History {
+    /// Records a change that was applied to the buffer. It becomes part of
+    /// the next step passed to `commit`.
+    pub(crate) fn record(&mut self, change: Change) {
+        self.pending.push(change);
+    }
+
+    /// Groups all changes recorded since the last commit into one undo step.
+    pub(crate) fn commit(&mut self, kind: EditKind, cursor_before: Cursor, cursor_after: Cursor) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let changes = mem::take(&mut self.pending);
+        if self.saved.map_or(false, |saved| saved > self.undo.len()) {
+            self.saved = None;
+        }
+        self.redo.clear();
+
+        let at_saved_state = self.saved == Some(self.undo.len());
+        match self.undo.last_mut() {
+            Some(last)
+                if kind == EditKind::Typing
+                    && last.kind == EditKind::Typing
+                    && last.cursor_after == cursor_before
+                    && !at_saved_state =>
+            {
+                last.changes.extend(changes);
+                last.cursor_after = cursor_after;
+            }
+            _ => self.undo.push(Step {
+                changes,
+                kind,
+                cursor_before,
+                cursor_after,
+            }),
+        }
+    }
+
+    /// Returns the changes that revert the last step, to be applied in order,
+    /// and the cursor position from before it was made.
+    pub(crate) fn undo(&mut self) -> Option<(Vec<Change>, Cursor)> {
+        let step = self.undo.pop()?;
+        let changes = step.changes.iter().rev().map(Change::inverse).collect();
+        let cursor = step.cursor_before;
+        self.redo.push(step);
+        Some((changes, cursor))
+    }
+
+    /// Returns the changes that reapply the last undone step and the cursor
+    /// position from after it was made.
+    pub(crate) fn redo(&mut self) -> Option<(Vec<Change>, Cursor)> {
+        let step = self.redo.pop()?;
+        let changes = step.changes.clone();
+        let cursor = step.cursor_after;
+        self.undo.push(step);
+        Some((changes, cursor))
+    }
+
+    pub(crate) fn mark_saved(&mut self) {
+        self.saved = Some(self.undo.len());
+    }
+
+    pub(crate) fn is_modified(&self) -> bool {
+        self.saved != Some(self.undo.len())
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    collections::VecDeque,
+    io,
+    time::Duration,
+};
+
+use libc::c_int;
+
+use crate::{
+    event_loop,
+    raw_bytes,
+};
+
+/// How long to wait for the rest of an escape sequence before taking what was
+/// read so far as complete, e. g. to tell a lone ESC from the start of a
+/// sequence.
+const SEQUENCE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Pasted texts and clipboard reports longer than this are dropped.
+const MAX_TEXT_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Decodes the UTF-8 sequence starting with `first`, reading continuation
+/// bytes from `next_byte` (which returns `None` when no more input is
+/// available).
+///
+/// Invalid, overlong or truncated sequences decode to the raw byte character
+/// of `first` (see `raw_bytes`), like they do in files. The bytes that were
+/// read after it are returned as well, so that they can be processed as the
+/// next keys.
+pub(crate) fn decode_utf8(first: u8, mut next_byte: impl FnMut() -> Option<u8>) -> (char, Vec<u8>) {
+    let (len, initial) = match first {
+        0x00..=0x7F => return (first.into(), vec![]),
+        0xC2..=0xDF => (2, first & 0x1F),
+        0xE0..=0xEF => (3, first & 0x0F),
+        0xF0..=0xF4 => (4, first & 0x07),
+        _ => return (raw_bytes::raw_char(first), vec![]),
+    };
+    // Restricting the second byte rules out overlong encodings, surrogates and
+    // code points above U+10FFFF as early as possible.
+    let second_byte = match first {
+        0xE0 => 0xA0..=0xBF,
+        0xED => 0x80..=0x9F,
+        0xF0 => 0x90..=0xBF,
+        0xF4 => 0x80..=0x8F,
+        _ => 0x80..=0xBF,
+    };
+    let mut code_point = u32::from(initial);
+    let mut read = vec![];
+    for idx in 1..len {
+        let expected = if idx == 1 {
+            second_byte.clone()
+        }
+        else {
+            0x80..=0xBF
+        };
+        let byte = next_byte();
+        read.extend(byte);
+        match byte {
+            Some(byte) if expected.contains(&byte) =>
+                code_point = (code_point << 6) | u32::from(byte & 0x3F),
+            _ => return (raw_bytes::raw_char(first), read),
+        }
+    }
+    (std::char::from_u32(code_point).unwrap(), vec![])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Key {
+    /// A character key. Control characters are reported as the corresponding
+    /// letter with `Modifiers::CTRL`.
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Esc,
+    Up,
+    Down,
+    Right,
+    Left,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    F(u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Modifiers {
+    pub(crate) shift: bool,
+    pub(crate) alt: bool,
+    pub(crate) ctrl: bool,
+}
+
+impl Modifiers {
+    pub(crate) const ALT: Modifiers = Modifiers { alt: true, ..Modifiers::NONE };
+    pub(crate) const CTRL: Modifiers = Modifiers { ctrl: true, ..Modifiers::NONE };
+    pub(crate) const NONE: Modifiers = Modifiers { shift: false, alt: false, ctrl: false };
+    pub(crate) const SHIFT: Modifiers = Modifiers { shift: true, ..Modifiers::NONE };
+
+    /// Decodes the modifier parameter of an escape sequence like `ESC [1;5C`,
+    /// which is one more than a bit mask of the pressed modifiers.
+    fn from_parameter(parameter: u32) -> Modifiers {
+        let mask = parameter.saturating_sub(1);
+        Modifiers {
+            shift: mask & 1 != 0,
+            alt: mask & 2 != 0,
+            ctrl: mask & 4 != 0,
+        }
+    }
+}
+
+/// A key together with the modifiers that were held while pressing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct KeyEvent {
+    pub(crate) key: Key,
+    pub(crate) modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    pub(crate) const fn new(key: Key, modifiers: Modifiers) -> KeyEvent {
+        KeyEvent { key, modifiers }
+    }
+
+    pub(crate) const fn plain(key: Key) -> KeyEvent {
+        KeyEvent::new(key, Modifiers::NONE)
+    }
+
+    pub(crate) const fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(Key::Char(c), Modifiers::CTRL)
+    }
+
+    fn with_alt(self) -> KeyEvent {
+        KeyEvent::new(self.key, Modifiers { alt: true, ..self.modifiers })
+    }
+}
+
+/// Input from the terminal.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    Key(KeyEvent),
+    /// The contents of the system clipboard, reported by the terminal in
+    /// response to `ansi::QUERY_CLIPBOARD`.
+    Clipboard(String),
+    /// Text pasted into the terminal while bracketed paste mode is enabled.
+    /// Line endings are normalised to `\n`.
+    Paste(String),
+}
+
+/// Parses the event starting with byte `first`, reading further bytes from
+/// `next_byte` (which returns `None` when no more input is available within
+/// `SEQUENCE_TIMEOUT`).
+///
+/// Returns `None` for escape sequences that are not understood. As with
+/// `decode_utf8`, bytes that were read but do not belong to the event are
+/// returned for reprocessing.
+pub(crate) fn parse_event(
+    first: u8,
+    mut next_byte: impl FnMut() -> Option<u8>,
+) -> (Option<Event>, Vec<u8>) {
+    let key = |key| Some(Event::Key(key));
+    match first {
+        0x1B => match next_byte() {
+            None => (key(KeyEvent::plain(Key::Esc)), vec![]),
+            Some(b'[') => parse_csi(next_byte),
+            Some(b']') => (parse_osc(next_byte), vec![]),
+            Some(b'O') => match next_byte() {
+                Some(byte) => (ss3_key(byte).map(Event::Key), vec![]),
+                None => (key(KeyEvent::new(Key::Char('O'), Modifiers::ALT)), vec![]),
+            },
+            Some(0x1B) => (key(KeyEvent::plain(Key::Esc)), vec![0x1B]),
+            Some(byte) => match parse_event(byte, next_byte) {
+                (Some(Event::Key(pressed)), unread) => (key(pressed.with_alt()), unread),
+                (_, unread) => (None, unread),
+            },
+        },
+        0x80..=0xFF => {
+            let (c, unread) = decode_utf8(first, next_byte);
+            (key(KeyEvent::plain(Key::Char(c))), unread)
+        }
+        _ => (key(control_key(first)), vec![]),
+    }
+}
+
+/// Maps an ASCII byte to a key, decoding control characters.
+fn control_key(byte: u8) -> KeyEvent {
+    match byte {
+        b'\r' => KeyEvent::plain(Key::Enter),
+        b'\t' => KeyEvent::plain(Key::Tab),
+        0x7F => KeyEvent::plain(Key::Backspace),
+        0x1B => KeyEvent::plain(Key::Esc),
+        0x00 => KeyEvent::ctrl(' '),
+        0x01..=0x1F => KeyEvent::ctrl(char::from(byte + 0x40).to_ascii_lowercase()),
+        _ => KeyEvent::plain(Key::Char(byte.into())),
+    }
+}
+
+/// Parses a control sequence (`ESC [` followed by numeric parameters
+/// separated by `;` and a final byte). Sequences with other parameter or
+/// intermediate bytes, e. g. the `?` of replies to queries or the `<` of mouse
+/// reports, are read up to their final byte and dropped. A byte that cannot be
+/// part of a control sequence ends it and is returned for reprocessing.
+fn parse_csi(mut next_byte: impl FnMut() -> Option<u8>) -> (Option<Event>, Vec<u8>) {
+    let mut parameters = vec![];
+    let mut parameter = None;
+    let mut understood = true;
+    loop {
+        let byte = match next_byte() {
+            Some(byte) => byte,
+            None => return (None, vec![]),
+        };
+        match byte {
+            b'0'..=b'9' => {
+                let digit = u32::from(byte - b'0');
+                parameter = Some(
+                    parameter
+                        .unwrap_or(0_u32)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+            }
+            b';' => parameters.push(parameter.take().unwrap_or(1)),
+            0x20..=0x3F => understood = false,
+            0x40..=0x7E if !understood => return (None, vec![]),
+            0x40..=0x7E => {
+                parameters.extend(parameter);
+                if byte == b'~' && parameters == [200] {
+                    return (parse_paste(next_byte), vec![]);
+                }
+                return (csi_key(byte, &parameters).map(Event::Key), vec![]);
+            }
+            _ => return (None, vec![byte]),
+        }
+    }
+}
+
+fn csi_key(final_byte: u8, parameters: &[u32]) -> Option<KeyEvent> {
+    let modifiers = Modifiers::from_parameter(parameters.get(1).copied().unwrap_or(1));
+    let key = match final_byte {
+        b'~' => match parameters.first()? {
+            1 | 7 => Key::Home,
+            2 => Key::Insert,
+            3 => Key::Delete,
+            4 | 8 => Key::End,
+            5 => Key::PageUp,
+            6 => Key::PageDown,
+            n @ 11..=15 => Key::F((n - 10) as u8),
+            n @ 17..=21 => Key::F((n - 11) as u8),
+            n @ 23..=24 => Key::F((n - 12) as u8),
+            _ => return None,
+        },
+        b'Z' => return Some(KeyEvent::new(Key::Tab, Modifiers::SHIFT)),
+        byte => return ss3_key(byte).map(|key| KeyEvent::new(key.key, modifiers)),
+    };
+    Some(KeyEvent::new(key, modifiers))
+}
+
+/// Reads pasted text up to the end of the bracketed paste (`ESC [201~`).
+/// Invalid UTF-8 is kept as raw bytes, like in files.
+fn parse_paste(mut next_byte: impl FnMut() -> Option<u8>) -> Option<Event> {
+    const END: &[u8] = b"\x1B[201~";
+    /// How long to wait for more pasted text (in `SEQUENCE_TIMEOUT`s) before
+    /// assuming that the end of the paste got lost.
+    const MAX_TIMEOUTS: usize = 10;
+
+    let mut pasted = vec![];
+    let mut truncated = false;
+    let mut timeouts = 0;
+    while !pasted.ends_with(END) {
+        match next_byte() {
+            Some(byte) => {
+                pasted.push(byte);
+                timeouts = 0;
+            }
+            None if timeouts < MAX_TIMEOUTS => timeouts += 1,
+            None => break,
+        }
+        if truncated || pasted.len() > MAX_TEXT_LENGTH + END.len() {
+            // Only the last bytes are kept, to find the end of the paste.
+            truncated = true;
+            pasted.drain(..pasted.len() - END.len());
+        }
+    }
+    if truncated {
+        return None;
+    }
+    if pasted.ends_with(END) {
+        pasted.truncate(pasted.len() - END.len());
+    }
+    let text = raw_bytes::decode(pasted)
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+    Some(Event::Paste(text))
+}
+
+/// Parses an operating system command (`ESC ]` followed by text that is
+/// terminated by BEL or `ESC \`). Only clipboard reports are understood.
+fn parse_osc(mut next_byte: impl FnMut() -> Option<u8>) -> Option<Event> {
+    let mut payload = vec![];
+    let mut truncated = false;
+    loop {
+        match next_byte()? {
+            0x07 => break,
+            0x1B => {
+                next_byte()?;
+                break;
+            }
+            byte if payload.len() < MAX_TEXT_LENGTH => payload.push(byte),
+            _ => truncated = true,
+        }
+    }
+    if truncated {
+        return None;
+    }
+    let payload = String::from_utf8(payload).ok()?;
+    let mut parts = payload.splitn(3, ';');
+    if parts.next() != Some("52") {
+        return None;
+    }
+    let data = crate::base64::decode(parts.nth(1)?)?;
+    Some(Event::Clipboard(raw_bytes::decode(data)))
+}
+
+/// Decodes the final byte shared by `ESC O` sequences and `ESC [` sequences
+/// without numeric parameters.
+fn ss3_key(byte: u8) -> Option<KeyEvent> {
+    let key = match byte {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        b'P'..=b'S' => Key::F(byte - b'P' + 1),
+        _ => return None,
+    };
+    Some(KeyEvent::plain(key))
+}
+
+/// Reads events from a terminal in raw mode.
+pub(crate) struct EventReader {
+    fd: c_int,
+    /// Bytes that were read but not used by the last event.
+    unread: VecDeque<u8>,
+}
+
+impl EventReader {
+    pub(crate) fn new(fd: c_int) -> EventReader {
+        EventReader { fd, unread: VecDeque::new() }
+    }
+
+    /// Reads a key press or a report from the terminal. Returns `None` if no
+    /// input is available.
+    pub(crate) fn read_event(&mut self) -> io::Result<Option<Event>> {
+        let fd = self.fd;
+        let mut error = None;
+        let event = next_event(&mut self.unread, |timeout| match read_byte(fd, timeout) {
+            Ok(byte) => byte,
+            Err(err) => {
+                error = Some(err);
+                None
+            }
+        });
+        match error {
+            Some(err) => Err(err),
+            None => Ok(event),
+        }
+    }
+}
+
+/// Parses the next event from the bytes in `unread` followed by those returned
+/// by `read_byte`, which waits up to the given time for one. Bytes that do not
+/// belong to the event are put back into `unread`. Returns `None` if no input
+/// is available.
+fn next_event(
+    unread: &mut VecDeque<u8>,
+    mut read_byte: impl FnMut(Duration) -> Option<u8>,
+) -> Option<Event> {
+    loop {
+        let first = match unread.pop_front() {
+            Some(byte) => byte,
+            None => read_byte(Duration::from_secs(0))?,
+        };
+        let (event, bytes) = parse_event(first, || {
+            unread.pop_front().or_else(|| read_byte(SEQUENCE_TIMEOUT))
+        });
+        for &byte in bytes.iter().rev() {
+            unread.push_front(byte);
+        }
+        if event.is_some() {
+            return event;
+        }
+    }
+}
+
+/// Reads one byte from `fd`, returning `None` if there was none within
+/// `timeout`.
+fn read_byte(fd: c_int, timeout: Duration) -> io::Result<Option<u8>> {
+    if !event_loop::wait_for_input(fd, timeout)? {
+        return Ok(None);
+    }
+    let mut byte = 0_u8;
+    loop {
+        match unsafe { libc::read(fd, &mut byte as *mut u8 as _, 1) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => return Ok(None),
+            _ => return Ok(Some(byte)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        rows::Rows,
+        Editor,
+    };
+
+    /// Parses the events in `chunks`, which arrive with pauses longer than
+    /// `SEQUENCE_TIMEOUT` in between.
+    fn events(chunks: &[&[u8]]) -> Vec<Event> {
+        let mut unread = VecDeque::new();
+        let mut events = vec![];
+        for chunk in chunks {
+            let mut bytes = chunk.iter().copied();
+            while let Some(event) = next_event(&mut unread, |_| bytes.next()) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    fn keys(chunks: &[&[u8]]) -> Vec<KeyEvent> {
+        events(chunks)
+            .into_iter()
+            .map(|event| match event {
+                Event::Key(key) => key,
+                event => panic!("not a key: {:?}", event),
+            })
+            .collect()
+    }
+
+    fn chars(chunks: &[&[u8]]) -> Vec<char> {
+        keys(chunks)
+            .into_iter()
+            .map(|key| match key {
+                KeyEvent {
+                    key: Key::Char(c),
+                    modifiers: Modifiers::NONE,
+                } => c,
+                key => panic!("not a character: {:?}", key),
+            })
+            .collect()
+    }
+
+    fn raw(bytes: &[u8]) -> Vec<char> {
+        bytes
+            .iter()
+            .map(|&byte| raw_bytes::raw_char(byte))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_multi_byte_characters() {
+        assert_eq!(chars(&["aäキ🦀".as_bytes()]), ['a', 'ä', 'キ', '🦀']);
+    }
+
+    #[test]
+    fn decodes_characters_split_across_reads() {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds;
+        let writer = thread::spawn(move || {
+            for part in &[&b"\xE3\x82"[..], b"\xAD\xC3", b"\xA4"] {
+                unsafe { libc::write(write_fd, part.as_ptr() as _, part.len()) };
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let mut reader = EventReader::new(read_fd);
+        let mut decoded = vec![];
+        while decoded.len() < 2 {
+            if let Some(Event::Key(KeyEvent { key: Key::Char(c), .. })) =
+                reader.read_event().unwrap()
+            {
+                decoded.push(c);
+            }
+        }
+        writer.join().unwrap();
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        assert_eq!(decoded, ['キ', 'ä']);
+    }
+
+    #[test]
+    fn invalid_bytes_become_raw_bytes() {
+        assert_eq!(chars(&[b"\xFF\x80"]), raw(b"\xFF\x80"));
+        let mut expected = raw(b"\xE4");
+        expected.push('a');
+        assert_eq!(chars(&[b"\xE4a"]), expected);
+        // A surrogate and a code point above U+10FFFF.
+        assert_eq!(chars(&[b"\xED\xA0\x80"]), raw(b"\xED\xA0\x80"));
+        assert_eq!(chars(&[b"\xF4\x90\x80\x80"]), raw(b"\xF4\x90\x80\x80"));
+    }
+
+    #[test]
+    fn overlong_sequences_become_raw_bytes() {
+        assert_eq!(chars(&[b"\xC0\xAF"]), raw(b"\xC0\xAF"));
+        assert_eq!(chars(&[b"\xE0\x80\xAF"]), raw(b"\xE0\x80\xAF"));
+        assert_eq!(chars(&[b"\xF0\x80\x80\xAF"]), raw(b"\xF0\x80\x80\xAF"));
+    }
+
+    #[test]
+    fn truncated_sequences_become_raw_bytes() {
+        let mut expected = raw(b"\xE3\x82");
+        expected.push('x');
+        assert_eq!(chars(&[b"\xE3\x82", b"x"]), expected);
+    }
+
+    #[test]
+    fn lone_escape_is_a_key() {
+        let esc = KeyEvent::plain(Key::Esc);
+        assert_eq!(keys(&[b"\x1B"]), [esc]);
+        assert_eq!(
+            keys(&[b"\x1B", b"a"]),
+            [esc, KeyEvent::plain(Key::Char('a'))]
+        );
+        assert_eq!(keys(&[b"\x1B\x1B"]), [esc, esc]);
+    }
+
+    #[test]
+    fn parses_home_end_variants() {
+        let home = KeyEvent::plain(Key::Home);
+        let end = KeyEvent::plain(Key::End);
+        assert_eq!(keys(&[b"\x1B[1~\x1B[7~\x1B[H\x1BOH"]), [home; 4]);
+        assert_eq!(keys(&[b"\x1B[4~\x1B[8~\x1B[F\x1BOF"]), [end; 4]);
+    }
+
+    #[test]
+    fn parses_other_tilde_sequences() {
+        assert_eq!(
+            keys(&[b"\x1B[2~\x1B[3~\x1B[5~\x1B[6~\x1B[15~\x1B[24~"]),
+            [
+                Key::Insert,
+                Key::Delete,
+                Key::PageUp,
+                Key::PageDown,
+                Key::F(5),
+                Key::F(12)
+            ]
+            .iter()
+            .map(|&key| KeyEvent::plain(key))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parses_modifiers() {
+        let ctrl_shift = Modifiers {
+            shift: true,
+            ctrl: true,
+            ..Modifiers::NONE
+        };
+        assert_eq!(
+            keys(&[b"\x1B[1;5C\x1B[1;2A\x1B[3;5~\x1B[1;6D\x1B[Z\x1Ba"]),
+            [
+                KeyEvent::new(Key::Right, Modifiers::CTRL),
+                KeyEvent::new(Key::Up, Modifiers::SHIFT),
+                KeyEvent::new(Key::Delete, Modifiers::CTRL),
+                KeyEvent::new(Key::Left, ctrl_shift),
+                KeyEvent::new(Key::Tab, Modifiers::SHIFT),
+                KeyEvent::new(Key::Char('a'), Modifiers::ALT),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_sequences_that_are_not_understood() {
+        // A reply to a device attributes query, a mouse report and an unknown
+        // key.
+        assert_eq!(
+            chars(&[b"\x1B[?64;1;2;6;9;15;18;21;22cx\x1B[<0;12;5My\x1B[99~z"]),
+            ['x', 'y', 'z']
+        );
+    }
+
+    #[test]
+    fn reprocesses_bytes_that_end_a_sequence_early() {
+        assert_eq!(keys(&[b"\x1B[1\x1B[A"]), [KeyEvent::plain(Key::Up)]);
+    }
+
+    #[test]
+    fn bracketed_paste_is_one_event() {
+        assert_eq!(
+            events(&[b"\x1B[200~a\r\nb\rc\n\x1B[A\x1B[201~x"]),
+            [
+                Event::Paste("a\nb\nc\n\x1B[A".into()),
+                Event::Key(KeyEvent::plain(Key::Char('x')))
+            ]
+        );
+    }
+
+    #[test]
+    fn pasted_text_keeps_raw_bytes() {
+        let mut expected: String = raw(b"\xFF\xE3\x82").into_iter().collect();
+        expected.push('a');
+        assert_eq!(
+            events(&[b"\x1B[200~\xFF\xE3\x82a\x1B[201~"]),
+            [Event::Paste(expected)]
+        );
+    }
+
+    #[test]
+    fn paste_without_end_is_taken_as_it_is() {
+        assert_eq!(
+            events(&[b"\x1B[200~abc\x1B[20", b"x"]),
+            [
+                Event::Paste("abc\x1B[20".into()),
+                Event::Key(KeyEvent::plain(Key::Char('x')))
+            ]
+        );
+    }
+
+    #[test]
+    fn overlong_paste_is_dropped() {
+        let mut bytes = b"\x1B[200~".to_vec();
+        bytes.resize(bytes.len() + MAX_TEXT_LENGTH + 1, b'a');
+        bytes.extend_from_slice(b"\x1B[201~x");
+        assert_eq!(chars(&[&bytes]), ['x']);
+    }
+
+    #[test]
+    fn paste_into_prompt_inserts_the_first_line() {
+        let mut editor = Editor {
+            rows: Rows::from(vec![String::new(); 5]),
+            ..Editor::default()
+        };
+        for event in events(&[b"\x07\x1B[200~3\r\n4\x1B[201~\r"]) {
+            assert!(editor.process_event(event));
+        }
+        assert!(!editor.is_prompting());
+        assert_eq!(editor.cy, 2);
+        assert_eq!(editor.rows.len(), 5);
+    }
+
+    #[test]
+    fn clipboard_reports_keep_raw_bytes() {
+        let mut expected = "a".to_owned();
+        expected.push(raw_bytes::raw_char(0xFF));
+        assert_eq!(
+            events(&[b"\x1B]52;c;Yf8=\x07"]),
+            [Event::Clipboard(expected)]
+        );
+    }
+}
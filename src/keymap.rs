@@ -0,0 +1,398 @@
+use crate::{
+    input::{
+        Event,
+        Key,
+        KeyEvent,
+        Modifiers,
+    },
+    Editor,
+};
+
+/// How many times Ctrl-Q has to be pressed to quit with unsaved changes.
+pub(crate) const QUIT_TIMES: usize = 3;
+
+impl Editor {
+    /// Handles input from the terminal. Returns `false` if the editor should
+    /// quit.
+    pub(crate) fn process_event(&mut self, event: Event) -> bool {
+        match event {
+            Event::Key(key) => self.process_keypress(key),
+            Event::Clipboard(text) => {
+                self.prepare_edit();
+                self.paste_from_system_clipboard(text);
+                true
+            }
+            Event::Paste(text) => {
+                self.finish_paste();
+                if self.is_prompting() {
+                    self.paste_into_prompt(&text);
+                }
+                else {
+                    self.prepare_edit();
+                    self.insert_pasted_text(&text);
+                }
+                true
+            }
+        }
+    }
+
+    /// Handles a key pressed by the user. Returns `false` if the editor should
+    /// quit.
+    pub(crate) fn process_keypress(&mut self, key: KeyEvent) -> bool {
+        const CTRL_SPACE: KeyEvent = KeyEvent::ctrl(' ');
+        const CTRL_C: KeyEvent = KeyEvent::ctrl('c');
+        const CTRL_F: KeyEvent = KeyEvent::ctrl('f');
+        const CTRL_G: KeyEvent = KeyEvent::ctrl('g');
+        const CTRL_H: KeyEvent = KeyEvent::ctrl('h');
+        const CTRL_L: KeyEvent = KeyEvent::ctrl('l');
+        const CTRL_Q: KeyEvent = KeyEvent::ctrl('q');
+        const CTRL_R: KeyEvent = KeyEvent::ctrl('r');
+        const CTRL_S: KeyEvent = KeyEvent::ctrl('s');
+        const CTRL_V: KeyEvent = KeyEvent::ctrl('v');
+        const CTRL_W: KeyEvent = KeyEvent::ctrl('w');
+        const CTRL_X: KeyEvent = KeyEvent::ctrl('x');
+        const CTRL_Y: KeyEvent = KeyEvent::ctrl('y');
+        const CTRL_Z: KeyEvent = KeyEvent::ctrl('z');
+        const ALT_L: KeyEvent = KeyEvent::new(Key::Char('l'), Modifiers::ALT);
+        const ALT_V: KeyEvent = KeyEvent::new(Key::Char('v'), Modifiers::ALT);
+
+        // While a prompt is shown, keys edit its input instead of the file.
+        if self.is_prompting() {
+            self.process_prompt_keypress(key);
+            return true;
+        }
+
+        if key != CTRL_V && key != ALT_V {
+            self.finish_paste();
+        }
+
+        // Moving the cursor with Shift held selects text.
+        let key = if is_movement(key.key) {
+            if key.modifiers.shift {
+                self.start_shift_selection();
+            }
+            else {
+                self.end_shift_selection();
+            }
+            KeyEvent::new(key.key, Modifiers { shift: false, ..key.modifiers })
+        }
+        else {
+            key
+        };
+
+        // Undo and redo only repeat edits that were possible already.
+        let edits = matches!(
+            key,
+            KeyEvent { key: Key::Enter, .. }
+                | KeyEvent { key: Key::Tab, .. }
+                | KeyEvent { key: Key::Backspace, .. }
+                | KeyEvent { key: Key::Delete, .. }
+                | KeyEvent {
+                    key: Key::Char(_),
+                    modifiers: Modifiers::NONE,
+                }
+                | CTRL_H
+                | CTRL_V
+                | CTRL_X
+                | ALT_V
+        );
+        if edits {
+            self.prepare_edit();
+        }
+
+        match key {
+            KeyEvent { key: Key::Enter, .. } => self.insert_newline(),
+            CTRL_SPACE => self.toggle_mark(),
+            CTRL_C => self.copy(),
+            CTRL_X => self.cut(),
+            CTRL_V => self.paste(),
+            ALT_V => self.paste_older(),
+            KeyEvent { key: Key::Esc, .. } => self.clear_mark(),
+            CTRL_Q => {
+                // Quit if the file was already saved.
+                if self.dirty && self.quit_times > 0 {
+                    self.set_status(format!(
+                        "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                        self.quit_times
+                    ));
+                    self.quit_times -= 1;
+                    return true;
+                }
+                return false;
+            }
+            CTRL_S =>
+                if self.filename.is_some() {
+                    self.save_unless_changed_on_disk();
+                }
+                else {
+                    self.start_save_as();
+                },
+            CTRL_W => self.start_save_as(),
+            CTRL_R => self.start_resolve_disk_change(),
+            CTRL_F => self.start_search(),
+            CTRL_G => self.start_goto_line(),
+            ALT_L => self.toggle_line_ending(),
+            CTRL_Z => self.undo(),
+            CTRL_Y => self.redo(),
+            KeyEvent { key: Key::Backspace, .. } | CTRL_H => self.delete_character(),
+            KeyEvent { key: Key::Delete, .. } => self.delete_next_character(),
+            KeyEvent {
+                key: Key::Home,
+                modifiers: Modifiers::CTRL,
+            } => self.move_to_buffer_start(),
+            KeyEvent {
+                key: Key::End,
+                modifiers: Modifiers::CTRL,
+            } => self.move_to_buffer_end(),
+            KeyEvent {
+                key: Key::Left,
+                modifiers: Modifiers::CTRL,
+            }
+            | KeyEvent {
+                key: Key::Right,
+                modifiers: Modifiers::CTRL,
+            } => self.move_cursor_by_word(key.key),
+            KeyEvent { key: Key::PageUp, .. }
+            | KeyEvent { key: Key::PageDown, .. }
+            | KeyEvent { key: Key::Up, .. }
+            | KeyEvent { key: Key::Down, .. }
+            | KeyEvent { key: Key::Left, .. }
+            | KeyEvent { key: Key::Right, .. }
+            | KeyEvent { key: Key::Home, .. }
+            | KeyEvent { key: Key::End, .. } => self.move_cursor(key.key),
+            // Ctrl-L just refreshes the screen as a side effect.
+            CTRL_L => (),
+            KeyEvent { key: Key::Tab, .. } => self.insert_char('\t'),
+            KeyEvent {
+                key: Key::Char(c),
+                modifiers: Modifiers::NONE,
+            } => self.insert_char(c),
+            _ => (),
+        }
+
+        self.quit_times = QUIT_TIMES;
+        true
+    }
+}
+
+fn is_movement(key: Key) -> bool {
+    matches!(
+        key,
+        Key::Up
+            | Key::Down
+            | Key::Left
+            | Key::Right
+            | Key::Home
+            | Key::End
+            | Key::PageUp
+            | Key::PageDown
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{
+            Rng,
+            TempDir,
+        },
+        SystemClipboard,
+    };
+
+    fn press(editor: &mut Editor, keys: &[KeyEvent]) {
+        for &key in keys {
+            assert!(editor.process_keypress(key));
+        }
+    }
+
+    fn type_text(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            let key = match c {
+                '\n' => KeyEvent::plain(Key::Enter),
+                c => KeyEvent::plain(Key::Char(c)),
+            };
+            press(editor, &[key]);
+        }
+    }
+
+    fn lines(editor: &Editor) -> Vec<&str> {
+        editor.rows.iter().collect()
+    }
+
+    #[test]
+    fn delete_removes_the_character_under_the_cursor() {
+        let mut editor = Editor::default();
+        type_text(&mut editor, "aäb");
+        press(
+            &mut editor,
+            &[KeyEvent::plain(Key::Home), KeyEvent::plain(Key::Right)],
+        );
+        press(&mut editor, &[KeyEvent::plain(Key::Delete)]);
+        assert_eq!(lines(&editor), ["ab"]);
+        assert_eq!((editor.cy, editor.cx), (0, 1));
+    }
+
+    #[test]
+    fn delete_at_the_end_of_a_line_joins_the_next_one() {
+        let mut editor = Editor::default();
+        type_text(&mut editor, "ab\ncd");
+        press(
+            &mut editor,
+            &[KeyEvent::plain(Key::Up), KeyEvent::plain(Key::End)],
+        );
+        press(&mut editor, &[KeyEvent::plain(Key::Delete)]);
+        assert_eq!(lines(&editor), ["abcd"]);
+        // There is nothing left to delete at the end of the buffer.
+        press(
+            &mut editor,
+            &[KeyEvent::plain(Key::End), KeyEvent::plain(Key::Delete)],
+        );
+        assert_eq!(lines(&editor), ["abcd"]);
+    }
+
+    #[test]
+    fn backspace_removes_the_character_before_the_cursor() {
+        let mut editor = Editor::default();
+        type_text(&mut editor, "ab\ncd");
+        press(
+            &mut editor,
+            &[KeyEvent::plain(Key::Home), KeyEvent::plain(Key::Backspace)],
+        );
+        assert_eq!(lines(&editor), ["abcd"]);
+        press(&mut editor, &[KeyEvent::ctrl('h')]);
+        assert_eq!(lines(&editor), ["acd"]);
+    }
+
+    #[test]
+    fn modified_keys_move_the_cursor() {
+        let mut editor = Editor::default();
+        type_text(&mut editor, "one two\nthree");
+        press(&mut editor, &[KeyEvent::new(Key::Home, Modifiers::CTRL)]);
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+        press(&mut editor, &[KeyEvent::new(Key::Right, Modifiers::CTRL)]);
+        assert_eq!((editor.cy, editor.cx), (0, 3));
+        press(&mut editor, &[KeyEvent::new(Key::End, Modifiers::CTRL)]);
+        assert_eq!((editor.cy, editor.cx), (1, 5));
+    }
+
+    #[test]
+    fn quitting_with_unsaved_changes_needs_confirmation() {
+        let mut editor = Editor::default();
+        assert!(!editor.process_keypress(KeyEvent::ctrl('q')));
+
+        type_text(&mut editor, "x");
+        for _ in 0..QUIT_TIMES {
+            assert!(editor.process_keypress(KeyEvent::ctrl('q')));
+        }
+        assert!(!editor.process_keypress(KeyEvent::ctrl('q')));
+    }
+
+    #[test]
+    fn random_edits_are_undone_and_redone() {
+        let keys = [
+            KeyEvent::plain(Key::Char('a')),
+            KeyEvent::plain(Key::Char('ä')),
+            KeyEvent::plain(Key::Char(' ')),
+            KeyEvent::plain(Key::Enter),
+            KeyEvent::plain(Key::Tab),
+            KeyEvent::plain(Key::Backspace),
+            KeyEvent::plain(Key::Delete),
+            KeyEvent::plain(Key::Left),
+            KeyEvent::plain(Key::Right),
+            KeyEvent::plain(Key::Up),
+            KeyEvent::plain(Key::Down),
+            KeyEvent::plain(Key::Home),
+            KeyEvent::plain(Key::End),
+            KeyEvent::new(Key::Left, Modifiers::SHIFT),
+            KeyEvent::new(Key::Up, Modifiers::SHIFT),
+            KeyEvent::new(Key::Left, Modifiers::CTRL),
+            KeyEvent::ctrl('x'),
+            KeyEvent::ctrl('c'),
+            KeyEvent::ctrl('v'),
+            KeyEvent::new(Key::Char('v'), Modifiers::ALT),
+            KeyEvent::ctrl('z'),
+            KeyEvent::ctrl('y'),
+        ];
+        for seed in 1..20 {
+            let mut rng = Rng::new(seed);
+            let mut editor = Editor::default();
+            editor.set_system_clipboard(SystemClipboard::Disabled);
+            for _ in 0..200 {
+                press(&mut editor, &[*rng.pick(&keys)]);
+            }
+            // Otherwise redoing could go past this state if the keys ended
+            // with undos.
+            type_text(&mut editor, "b");
+            let edited: Vec<_> = editor.rows.iter().map(str::to_owned).collect();
+
+            for _ in 0..200 {
+                press(&mut editor, &[KeyEvent::ctrl('z')]);
+            }
+            assert!(editor.rows.is_empty());
+            assert_eq!((editor.cy, editor.cx), (0, 0));
+            assert!(!editor.dirty);
+
+            for _ in 0..200 {
+                press(&mut editor, &[KeyEvent::ctrl('y')]);
+            }
+            assert_eq!(lines(&editor), edited);
+        }
+    }
+
+    #[test]
+    fn typing_is_undone_in_one_step() {
+        let mut editor = Editor::default();
+        type_text(&mut editor, "one two");
+        press(&mut editor, &[KeyEvent::plain(Key::Left)]);
+        type_text(&mut editor, "x\nab");
+        assert_eq!(lines(&editor), ["one twx", "abo"]);
+
+        // Typing after moving the cursor or after Enter starts a new step.
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        assert_eq!(lines(&editor), ["one twx", "o"]);
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        assert_eq!(lines(&editor), ["one twxo"]);
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        assert_eq!(lines(&editor), ["one two"]);
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        assert!(editor.rows.is_empty());
+
+        press(&mut editor, &[KeyEvent::ctrl('y'), KeyEvent::ctrl('y')]);
+        assert_eq!(lines(&editor), ["one twxo"]);
+        assert_eq!((editor.cy, editor.cx), (0, 7));
+    }
+
+    #[test]
+    fn undoing_back_to_the_saved_state_clears_dirty() {
+        let dir = TempDir::new();
+        let mut editor = Editor::default();
+        editor.open(dir.path().join("file")).unwrap();
+        type_text(&mut editor, "ab");
+        editor.save().unwrap();
+        assert!(!editor.dirty);
+
+        type_text(&mut editor, "c");
+        assert!(editor.dirty);
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        assert_eq!(lines(&editor), ["ab"]);
+        assert!(!editor.dirty);
+        press(&mut editor, &[KeyEvent::ctrl('y')]);
+        assert!(editor.dirty);
+
+        // Typing right after saving is not merged with the saved step.
+        press(&mut editor, &[KeyEvent::ctrl('z'), KeyEvent::ctrl('z')]);
+        assert!(editor.rows.is_empty());
+        assert!(editor.dirty);
+        press(&mut editor, &[KeyEvent::ctrl('y')]);
+        assert!(!editor.dirty);
+
+        // Once another edit replaced the undone ones, the saved state cannot
+        // be reached anymore.
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        type_text(&mut editor, "x");
+        press(&mut editor, &[KeyEvent::ctrl('z')]);
+        assert!(editor.rows.is_empty());
+        assert!(editor.dirty);
+    }
+}
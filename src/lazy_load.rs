@@ -0,0 +1,740 @@
+//! Opening huge files without reading them first: the file is mapped into
+//! memory and a background thread finds its lines, which are added to the
+//! buffer in batches while the editor already shows the first screen. Lines
+//! refer to the mapping until they are edited (see `rows`).
+//!
+//! Another program truncating the file while it is mapped makes accessing the
+//! missing part fail with `SIGBUS`, a risk that has to be taken to avoid
+//! copying the file. The periodic check for changes on disk notices a shorter
+//! file (see `Mapping::is_shrunk_at`), and the `SIGBUS` handler covers
+//! accesses before that (see `replace_missing_pages`). Either way, the buffer
+//! stops referring to the file (see `Editor::drop_truncated_mapping`).
+
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    io,
+    os::unix::{
+        fs::MetadataExt,
+        io::AsRawFd,
+    },
+    path::Path,
+    ptr,
+    slice,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        mpsc::{
+            self,
+            Receiver,
+            Sender,
+            TryRecvError,
+        },
+        Arc,
+    },
+    thread,
+};
+
+use libc::{
+    c_int,
+    c_void,
+};
+
+use crate::{
+    file_format::{
+        FileFormat,
+        LineEnding,
+    },
+    history::History,
+    raw_bytes,
+    rows::{
+        MappedLine,
+        Rows,
+    },
+    syntax::Highlighter,
+    terminal,
+    Editor,
+};
+
+/// Files of at least this size are loaded lazily, smaller ones are read
+/// completely.
+pub(crate) const MIN_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Number of lines in the first batch, which is waited for before the file is
+/// shown, so that the first screen is not empty.
+const FIRST_BATCH_LEN: usize = 1024;
+/// Number of lines in every later batch.
+const BATCH_LEN: usize = 64 * 1024;
+
+/// Where a file is mapped, so that the `SIGBUS` handler can tell whether a
+/// fault happened in it.
+struct MappedRange {
+    /// The address of the mapping, or `0` if the slot is unused.
+    start: AtomicUsize,
+    len: AtomicUsize,
+    /// Whether part of the mapping was replaced by zeros.
+    truncated: AtomicBool,
+}
+
+impl MappedRange {
+    const fn unused() -> MappedRange {
+        MappedRange {
+            start: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            truncated: AtomicBool::new(false),
+        }
+    }
+}
+
+/// The ranges of all mappings. There are rarely more than two: the one of the
+/// buffer, and the previous one while its thread has not yet noticed that the
+/// file was reloaded.
+static MAPPED_RANGES: [MappedRange; 4] = [
+    MappedRange::unused(),
+    MappedRange::unused(),
+    MappedRange::unused(),
+    MappedRange::unused(),
+];
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// A file mapped into memory, read-only.
+pub(crate) struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+    /// The index of the mapping in `MAPPED_RANGES`.
+    slot: usize,
+    /// The device and inode of the mapped file.
+    file_id: (u64, u64),
+}
+
+// The mapping is never written to.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the first `len` bytes of `file`, which must not be zero.
+    pub(crate) fn new(file: &File, len: usize) -> io::Result<Mapping> {
+        let metadata = file.metadata()?;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let slot = MAPPED_RANGES.iter().position(|range| {
+            range
+                .start
+                .compare_exchange(0, ptr as usize, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                unsafe {
+                    libc::munmap(ptr, len);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Too many mapped files",
+                ));
+            }
+        };
+        let range = &MAPPED_RANGES[slot];
+        range.truncated.store(false, Ordering::SeqCst);
+        range.len.store(len, Ordering::SeqCst);
+        PAGE_SIZE.store(
+            unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize,
+            Ordering::SeqCst,
+        );
+        Ok(Mapping {
+            ptr,
+            len,
+            slot,
+            file_id: (metadata.dev(), metadata.ino()),
+        })
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    /// Tells whether the file was truncated by another program, so that part
+    /// of the mapping was replaced by zeros.
+    pub(crate) fn is_truncated(&self) -> bool {
+        MAPPED_RANGES[self.slot].truncated.load(Ordering::SeqCst)
+    }
+
+    /// Tells whether the file at `path` is the mapped one and another program
+    /// made it shorter than the mapping, so that reading the end of the
+    /// mapping would fail.
+    pub(crate) fn is_shrunk_at(&self, path: &Path) -> bool {
+        fs::metadata(path).map_or(false, |metadata| {
+            (metadata.dev(), metadata.ino()) == self.file_id && metadata.size() < self.len as u64
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let range = &MAPPED_RANGES[self.slot];
+        range.len.store(0, Ordering::SeqCst);
+        range.start.store(0, Ordering::SeqCst);
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// What the background thread found out about the file.
+enum Message {
+    /// The next lines, which end at byte `end` of the file.
+    Lines {
+        lines: Vec<MappedLine>,
+        end: usize,
+        contains_nul: bool,
+    },
+    /// All lines were sent. The counts are those of the line endings in the
+    /// file.
+    Done {
+        lf_count: usize,
+        crlf_count: usize,
+        final_newline: bool,
+    },
+}
+
+/// The state of a file that is still being loaded.
+pub(crate) struct Loader {
+    receiver: Receiver<Message>,
+    /// Whether the lines are taken to be CRLF terminated, which is guessed
+    /// from the first line and checked when all lines are known.
+    crlf: bool,
+    size: usize,
+    /// Number of bytes whose lines were received.
+    loaded: usize,
+    warned_about_nul: bool,
+}
+
+impl Loader {
+    /// Returns how much of the file was loaded, in percent.
+    pub(crate) fn progress(&self) -> usize {
+        self.loaded * 100 / self.size
+    }
+}
+
+impl Editor {
+    /// Starts loading the nonempty `file` lazily and waits for its first
+    /// lines.
+    pub(crate) fn start_loading(&mut self, file: &File) -> io::Result<()> {
+        let size = file.metadata()?.len() as usize;
+        let mapping = Arc::new(Mapping::new(file, size)?);
+        let bytes = mapping.as_bytes();
+        let crlf = match find_byte(bytes, b'\n') {
+            Some(end) => end > 0 && bytes[end - 1] == b'\r',
+            None => false,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let thread_mapping = Arc::clone(&mapping);
+        thread::Builder::new()
+            .name("lazy load".into())
+            .spawn(move || find_lines(&thread_mapping, crlf, &sender))?;
+
+        self.rows = Rows::from_mapping(mapping);
+        let line_ending = if crlf {
+            LineEnding::CrLf
+        }
+        else {
+            LineEnding::Lf
+        };
+        self.file_format = FileFormat::new(line_ending, true);
+        self.saved_file_format = self.file_format;
+        self.loader = Some(Loader {
+            receiver,
+            crlf,
+            size,
+            loaded: 0,
+            warned_about_nul: false,
+        });
+        if let Some(message) = self
+            .loader
+            .as_ref()
+            .and_then(|loader| loader.receiver.recv().ok())
+        {
+            self.lines_received(message);
+        }
+        Ok(())
+    }
+
+    /// Adds the next batch of lines to the buffer if it was found already.
+    /// Returns whether there was one. Only one batch is taken at a time, so
+    /// that input is still handled while the lines come in faster than they
+    /// can be highlighted.
+    pub(crate) fn receive_lines(&mut self) -> bool {
+        let loader = match &self.loader {
+            Some(loader) => loader,
+            None => return false,
+        };
+        match loader.receiver.try_recv() {
+            Ok(message) => self.lines_received(message),
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => self.loading_failed(),
+        }
+        true
+    }
+
+    /// Waits until the whole file is loaded. This is needed before the
+    /// buffer is used as a whole.
+    pub fn finish_loading(&mut self) {
+        while self.loader.is_some() {
+            self.wait_for_lines();
+        }
+    }
+
+    /// Like `finish_loading`, but redraws the screen after every batch of
+    /// lines, so that the user sees the progress in the status bar.
+    pub(crate) fn wait_for_loading(&mut self) {
+        while self.loader.is_some() {
+            self.wait_for_lines();
+            if terminal::is_active() {
+                // Errors show up again when the main loop draws the screen.
+                self.draw().ok();
+            }
+        }
+    }
+
+    /// Waits until the whole file is loaded if an edit at the cursor (or the
+    /// mark) could depend on lines that were not found yet, i. e. if it is on
+    /// the last line found so far or after it. Lines before that stay the same
+    /// while more are added, so they can be edited right away. Unless the
+    /// lines are taken to be CRLF terminated: every edit waits then, as the
+    /// carriage returns may have to be put back, which only works for lines
+    /// that were not edited (see `Rows::restore_carriage_returns`).
+    pub(crate) fn prepare_edit(&mut self) {
+        let crlf = match &self.loader {
+            Some(loader) => loader.crlf,
+            None => return,
+        };
+        let last_row = self.mark.map_or(self.cy, |mark| mark.row.max(self.cy));
+        if crlf || last_row + 1 >= self.rows.len() {
+            self.wait_for_loading();
+        }
+    }
+
+    fn wait_for_lines(&mut self) {
+        if let Some(loader) = &self.loader {
+            match loader.receiver.recv() {
+                Ok(message) => self.lines_received(message),
+                Err(_) => self.loading_failed(),
+            }
+        }
+    }
+
+    /// Called when the thread finding the lines stopped before it sent all of
+    /// them. Saving the lines found so far would cut the file short, so the
+    /// buffer no longer refers to the file. Changes that were made while
+    /// loading are kept, so that they can be saved to another file. Otherwise
+    /// the buffer is cleared.
+    fn loading_failed(&mut self) {
+        self.loader = None;
+        let filename = self.filename.take().unwrap_or_default();
+        let found = self.rows.len();
+        if self.dirty {
+            self.remember_disk_state();
+            self.set_status(format!(
+                "Error: loading `{}` failed after {} lines, save the changes to another file",
+                filename.display(),
+                found
+            ));
+            return;
+        }
+        self.rows = Rows::default();
+        self.highlighter = Highlighter::new(self.highlighter.syntax(), &self.rows);
+        self.history = History::default();
+        self.search = None;
+        self.prompt = None;
+        self.clear_mark();
+        self.set_cursor(0, 0);
+        self.remember_disk_state();
+        self.set_status(format!(
+            "Error: loading `{}` failed after {} lines, the buffer was cleared",
+            filename.display(),
+            found
+        ));
+    }
+
+    /// Called after `replace_missing_pages` replaced part of the mapped file by
+    /// zeros.
+    pub(crate) fn mapped_file_truncated(&mut self) {
+        if self.rows.mapping().map_or(false, Mapping::is_truncated) {
+            self.drop_truncated_mapping();
+        }
+    }
+
+    /// Stops referring to a mapped file that another program truncated. An
+    /// unchanged buffer is reloaded. A changed one is copied, so that it no
+    /// longer refers to the file, and keeps zeros (which show up as NUL bytes)
+    /// in place of the lines that are gone.
+    pub(crate) fn drop_truncated_mapping(&mut self) {
+        if self.dirty || self.is_prompting() {
+            self.rows.copy_mapping();
+            self.disk.changed = true;
+            self.set_status(format!(
+                "`{}` was truncated by another program, the lines past its end are lost. \
+                 Press Ctrl-R to reload, overwrite or diff it",
+                self.filename()
+            ));
+        }
+        else {
+            self.reload();
+        }
+    }
+
+    pub(crate) fn loader(&self) -> Option<&Loader> {
+        self.loader.as_ref()
+    }
+
+    fn lines_received(&mut self, message: Message) {
+        let loader = self.loader.as_mut().unwrap();
+        match message {
+            Message::Lines { lines, end, contains_nul } => {
+                loader.loaded = end;
+                let warn = contains_nul && !loader.warned_about_nul;
+                loader.warned_about_nul |= contains_nul;
+                self.rows.append(lines);
+                self.highlighter.rows_appended(&self.rows);
+                if warn {
+                    self.warn_about_binary_file();
+                }
+            }
+            Message::Done { lf_count, crlf_count, final_newline } => {
+                let crlf = loader.crlf;
+                self.loader = None;
+                if crlf && lf_count > 0 {
+                    // Like `FileFormat::read`, mixed line endings are kept as
+                    // they are.
+                    self.rows.restore_carriage_returns();
+                    self.highlighter = Highlighter::new(self.highlighter.syntax(), &self.rows);
+                }
+                let line_ending = if crlf_count > 0 && lf_count == 0 {
+                    LineEnding::CrLf
+                }
+                else {
+                    LineEnding::Lf
+                };
+                self.file_format = FileFormat::new(line_ending, final_newline);
+                self.saved_file_format = self.file_format;
+            }
+        }
+    }
+}
+
+/// Called by the `SIGBUS` handler when accessing `address` failed. If it is
+/// part of a mapping, the file was truncated by another program. The mapping
+/// is then replaced by zeros from the page of `address` on, so that accessing
+/// it can go on. Returns whether that happened. Only calls functions that can
+/// be used in a signal handler.
+pub(crate) fn replace_missing_pages(address: usize) -> bool {
+    for range in &MAPPED_RANGES {
+        let start = range.start.load(Ordering::SeqCst);
+        let len = range.len.load(Ordering::SeqCst);
+        if start <= address && address < start + len {
+            let page = address - (address - start) % PAGE_SIZE.load(Ordering::SeqCst);
+            let zeros = unsafe {
+                libc::mmap(
+                    page as *mut c_void,
+                    start + len - page,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            };
+            if zeros == libc::MAP_FAILED {
+                return false;
+            }
+            range.truncated.store(true, Ordering::SeqCst);
+            return true;
+        }
+    }
+    false
+}
+
+/// Sends the lines of `mapping` in batches, without the `\r` of CRLF line
+/// endings if `crlf` is set. Stops early when nobody is receiving anymore,
+/// i. e. another file was loaded.
+fn find_lines(mapping: &Mapping, crlf: bool, sender: &Sender<Message>) {
+    let bytes = mapping.as_bytes();
+    let (mut lf_count, mut crlf_count) = (0, 0);
+    let mut start = 0;
+    let mut batch_len = FIRST_BATCH_LEN;
+    while start < bytes.len() {
+        let batch_start = start;
+        let mut lines = Vec::with_capacity(batch_len);
+        while lines.len() < batch_len && start < bytes.len() {
+            let (mut end, next) = match find_byte(&bytes[start..], b'\n') {
+                Some(len) => (start + len, start + len + 1),
+                None => (bytes.len(), bytes.len()),
+            };
+            if next > end {
+                if end > start && bytes[end - 1] == b'\r' {
+                    crlf_count += 1;
+                    if crlf {
+                        end -= 1;
+                    }
+                }
+                else {
+                    lf_count += 1;
+                }
+            }
+            let line = &bytes[start..end];
+            lines.push(if raw_bytes::is_plain(line) {
+                MappedLine::Plain(start..end)
+            }
+            else {
+                MappedLine::Decoded {
+                    range: start..end,
+                    text: raw_bytes::decode(line.to_vec()),
+                }
+            });
+            start = next;
+        }
+        let contains_nul = find_byte(&bytes[batch_start..start], 0).is_some();
+        if sender
+            .send(Message::Lines { lines, end: start, contains_nul })
+            .is_err()
+        {
+            return;
+        }
+        terminal::wake_up();
+        batch_len = BATCH_LEN;
+    }
+    let final_newline = bytes.last().map_or(true, |&byte| byte == b'\n');
+    sender
+        .send(Message::Done { lf_count, crlf_count, final_newline })
+        .ok();
+    terminal::wake_up();
+}
+
+/// Returns the offset of the first `byte` in `bytes`.
+fn find_byte(bytes: &[u8], byte: u8) -> Option<usize> {
+    let found = unsafe { libc::memchr(bytes.as_ptr() as _, c_int::from(byte), bytes.len()) };
+    if found.is_null() {
+        None
+    }
+    else {
+        Some(found as usize - bytes.as_ptr() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        ops::Range,
+    };
+
+    use super::*;
+    use crate::{
+        input::{
+            Key,
+            KeyEvent,
+        },
+        test_util::{
+            lock_mappings,
+            TempDir,
+        },
+    };
+
+    fn numbered_text(range: Range<usize>, line_ending: &str) -> String {
+        range
+            .map(|n| format!("line {}{}", n, line_ending))
+            .collect()
+    }
+
+    /// Writes `bytes` to a file in `dir` and opens it lazily.
+    fn open_lazily(dir: &TempDir, bytes: &[u8]) -> Editor {
+        let path = dir.path().join("file");
+        fs::write(&path, bytes).unwrap();
+        let mut editor = Editor {
+            lazy_load_min_size: 1,
+            ..Editor::default()
+        };
+        editor.open(&path).unwrap();
+        assert!(editor.rows.mapping().is_some());
+        editor
+    }
+
+    fn path(editor: &Editor) -> &Path {
+        editor.filename.as_deref().unwrap()
+    }
+
+    fn type_char(editor: &mut Editor, c: char) {
+        assert!(editor.process_keypress(KeyEvent::plain(Key::Char(c))));
+    }
+
+    /// Makes the editor act as if the thread finding the lines stopped early.
+    fn disconnect_loader(editor: &mut Editor) {
+        let (_, receiver) = mpsc::channel();
+        editor.loader = Some(Loader {
+            receiver,
+            crlf: false,
+            size: 1,
+            loaded: 0,
+            warned_about_nul: false,
+        });
+    }
+
+    #[test]
+    fn loaded_lines_match_read_lines() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mixed = numbered_text(0..10, "\r\n") + &numbered_text(10..FIRST_BATCH_LEN * 2, "\n");
+        let mut raw = numbered_text(0..FIRST_BATCH_LEN * 2, "\n").into_bytes();
+        raw.extend_from_slice(b"\xFF\0\xF4\x8F\xBE\x80");
+        for bytes in &[
+            numbered_text(0..FIRST_BATCH_LEN * 3, "\n").into_bytes(),
+            numbered_text(0..FIRST_BATCH_LEN * 3, "\r\n").into_bytes(),
+            mixed.into_bytes(),
+            raw,
+            b"one line".to_vec(),
+        ] {
+            let mut editor = open_lazily(&dir, bytes);
+            assert!(!editor.rows.is_empty());
+            editor.finish_loading();
+            let (rows, file_format) = FileFormat::read(&bytes[..]).unwrap();
+            assert!(editor.rows.iter().eq(rows.iter()));
+            assert!(editor.file_format == file_format);
+            editor.save().unwrap();
+            assert_eq!(&fs::read(path(&editor)).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn lines_can_be_edited_while_loading() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mut editor = open_lazily(&dir, numbered_text(0..FIRST_BATCH_LEN * 3, "\n").as_bytes());
+        type_char(&mut editor, 'x');
+        // The last line found so far may still be continued.
+        let last_found = editor.rows.len() - 1;
+        editor.set_cursor(last_found, 0);
+        type_char(&mut editor, 'y');
+        assert!(editor.loader.is_none());
+
+        let mut expected: Vec<_> = (0..FIRST_BATCH_LEN * 3)
+            .map(|n| format!("line {}\n", n))
+            .collect();
+        expected[0].insert(0, 'x');
+        expected[last_found].insert(0, 'y');
+        editor.save().unwrap();
+        assert_eq!(
+            fs::read_to_string(path(&editor)).unwrap(),
+            expected.concat()
+        );
+    }
+
+    #[test]
+    fn crlf_files_are_loaded_before_edits() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mut editor = open_lazily(
+            &dir,
+            numbered_text(0..FIRST_BATCH_LEN * 3, "\r\n").as_bytes(),
+        );
+        type_char(&mut editor, 'x');
+        assert!(editor.loader.is_none());
+        assert_eq!(&editor.rows[0], "xline 0");
+    }
+
+    #[test]
+    fn failed_loading_clears_an_unchanged_buffer() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mut editor = open_lazily(&dir, numbered_text(0..10, "\n").as_bytes());
+        disconnect_loader(&mut editor);
+        editor.finish_loading();
+        assert!(editor.rows.is_empty());
+        assert!(editor.filename.is_none());
+        assert!(!editor.dirty);
+    }
+
+    #[test]
+    fn failed_loading_keeps_changes() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let text = numbered_text(0..10, "\n");
+        let mut editor = open_lazily(&dir, text.as_bytes());
+        let path = path(&editor).to_owned();
+        type_char(&mut editor, 'x');
+        disconnect_loader(&mut editor);
+        editor.finish_loading();
+        assert_eq!(editor.rows.len(), 10);
+        assert_eq!(&editor.rows[0], "xline 0");
+        assert!(editor.dirty);
+        // Saving must not overwrite the file with the lines found so far.
+        assert!(editor.filename.is_none());
+        assert_eq!(fs::read_to_string(path).unwrap(), text);
+    }
+
+    #[test]
+    fn shorter_file_is_reloaded_if_unchanged() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mut editor = open_lazily(&dir, numbered_text(0..FIRST_BATCH_LEN * 3, "\n").as_bytes());
+        editor.finish_loading();
+        fs::write(path(&editor), "new\n").unwrap();
+        assert!(editor.check_disk());
+        assert!(editor.rows.iter().eq(vec!["new"]));
+        assert!(!editor.dirty);
+    }
+
+    #[test]
+    fn shorter_file_is_copied_if_changed() {
+        let _lock = lock_mappings();
+        terminal::catch_signals().unwrap();
+        let dir = TempDir::new();
+        let mut editor = open_lazily(&dir, numbered_text(0..FIRST_BATCH_LEN * 3, "\n").as_bytes());
+        editor.finish_loading();
+        type_char(&mut editor, 'x');
+        fs::write(path(&editor), "new\n").unwrap();
+        assert!(editor.check_disk());
+        assert!(editor.rows.mapping().is_none());
+        assert!(editor.dirty);
+        assert!(editor.disk.changed);
+        assert_eq!(editor.rows.len(), FIRST_BATCH_LEN * 3);
+        assert_eq!(&editor.rows[0], "xline 0");
+        // The lines past the end of the file are gone.
+        assert!(editor.rows.last().unwrap().chars().all(|c| c == '\0'));
+    }
+
+    #[test]
+    fn truncated_mapping_is_replaced_by_zeros() {
+        let _lock = lock_mappings();
+        terminal::catch_signals().unwrap();
+        let dir = TempDir::new();
+        let mut editor = open_lazily(&dir, numbered_text(0..FIRST_BATCH_LEN * 3, "\n").as_bytes());
+        editor.finish_loading();
+        File::create(path(&editor)).unwrap();
+        // Reading the last line fails with `SIGBUS`, which is recovered from.
+        let last = editor.rows.last().unwrap();
+        assert!(!last.is_empty() && last.chars().all(|c| c == '\0'));
+        assert!(editor.rows.mapping().unwrap().is_truncated());
+        assert!(terminal::take_signals().unwrap().contains(&libc::SIGBUS));
+
+        editor.mapped_file_truncated();
+        assert!(editor.rows.is_empty());
+        assert!(!editor.dirty);
+    }
+}
@@ -3,22 +3,14 @@
 
 use std::{
     borrow::Cow,
-    ffi::{
-        CStr,
-        NulError,
-    },
+    ffi::NulError,
     fmt::{
         self,
         Write as FmtWrite,
     },
-    fs::{
-        rename,
-        File,
-    },
+    fs::File,
     io::{
         self,
-        BufRead,
-        BufReader,
         Write as IoWrite,
     },
     iter,
@@ -32,38 +24,65 @@ use std::{
     },
 };
 
-use libc::{
-    c_char,
-    c_int,
-};
+use libc::c_int;
+
+use ansi::Color;
 
 pub mod ansi;
+mod base64;
+mod clipboard;
+mod disk;
+mod event_loop;
+mod file_format;
+mod history;
+mod input;
+mod keymap;
+mod lazy_load;
+mod prompt;
+mod raw_bytes;
+mod recovery;
+pub mod rows;
+mod save;
+mod search;
+mod syntax;
+pub mod terminal;
+#[cfg(test)]
+mod test_util;
+mod unicode;
+
+use event_loop::Timer;
+use file_format::FileFormat;
+use history::{
+    Change,
+    EditKind,
+    History,
+};
+use input::Key;
+use rows::Rows;
+use syntax::Highlighter;
+
+pub use clipboard::SystemClipboard;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
-pub const HELP_MESSAGE: &str = "HELP: Ctrl-S = save | Ctrl-Q = quit";
+pub const HELP_MESSAGE: &str =
+    "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find | Ctrl-Z/Ctrl-Y = undo/redo";
 
 pub const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
-
-#[link(name = "c")]
-extern "C" {
-    fn wcwidth(c: libc::wchar_t) -> c_int;
-}
+pub const DEFAULT_TAB_WIDTH: usize = 4;
 
 #[link(name = "kilo", kind = "static")]
 extern "C" {
-    // FIXME: This warning is a bug, see https://github.com/rust-lang/rust/pull/72700 and
-    // https://github.com/rust-lang/rust/pull/74448
-    #[allow(improper_ctypes)]
-    static mut E: Editor;
+    fn getWindowSize(ifd: c_int, ofd: c_int, rows: *mut usize, cols: *mut usize) -> c_int;
 }
 
 #[derive(Debug)]
 pub enum Error {
-    IncorrectInvocation,
     NoFileOpen,
     NulError(NulError),
     IoError(io::Error),
     FmtError(fmt::Error),
+    /// The editor was ended by a signal.
+    Signal(c_int),
 }
 
 impl From<NulError> for Error {
@@ -86,50 +105,44 @@ impl From<fmt::Error> for Error {
 
 pub type KiroResult<T> = Result<T, Error>;
 
-#[repr(C)]
-#[allow(non_camel_case_types)]
-pub enum KEY_ACTION {
-    KEY_NULL = 0,    // NULL
-    CTRL_C = 3,      // Ctrl-c
-    CTRL_D = 4,      // Ctrl-d
-    CTRL_H = 8,      // Ctrl-h
-    TAB = 9,         // Tab
-    CTRL_L = 12,     // Ctrl+l
-    ENTER = 13,      // Enter
-    CTRL_Q = 17,     // Ctrl-q
-    CTRL_S = 19,     // Ctrl-s
-    CTRL_U = 21,     // Ctrl-u
-    ESC = 27,        // Escape
-    BACKSPACE = 127, // Backspace
-    // The following are just soft codes, not really reported by the
-    // terminal directly.
-    ARROW_LEFT = 1000,
-    ARROW_RIGHT,
-    ARROW_UP,
-    ARROW_DOWN,
-    DEL_KEY,
-    HOME_KEY,
-    END_KEY,
-    PAGE_UP,
-    PAGE_DOWN,
-}
-
-type Rows = Vec<String>;
-
-#[repr(C)]
 pub struct Editor {
+    /// Byte offset of the cursor in its row, always on a grapheme cluster
+    /// boundary. Its display column is given by `rx()`.
     cx: usize,
+    /// Row of the cursor in the file.
     cy: usize,
+    /// First row of the file shown on screen.
     rowoff: usize,
+    /// First display column shown on screen.
     coloff: usize,
     screenrows: usize,
     screencols: usize,
-    numrows: usize,
-    rawmode: usize,
-    rows: Box<Rows>,
+    /// Distance between tab stops in columns.
+    tab_width: usize,
+    rows: Rows,
     dirty: bool,
-    filename: Box<Option<PathBuf>>,
-    status: Box<Status>,
+    filename: Option<PathBuf>,
+    status: Status,
+    search: Option<search::Search>,
+    prompt: Option<prompt::Prompt>,
+    prompt_history: prompt::PromptHistory,
+    quit_times: usize,
+    history: History,
+    highlighter: Highlighter,
+    mark: Option<clipboard::Mark>,
+    kill_ring: clipboard::KillRing,
+    system_clipboard: SystemClipboard,
+    file_format: FileFormat,
+    /// The format of the file on disk, to tell whether `file_format` was
+    /// changed since the last save.
+    saved_file_format: FileFormat,
+    disk: disk::DiskState,
+    recovery: recovery::Recovery,
+    timers: event_loop::Timers,
+    /// Set while a huge file is loaded in the background.
+    loader: Option<lazy_load::Loader>,
+    /// Files of at least this size are loaded lazily (see `lazy_load`).
+    lazy_load_min_size: u64,
 }
 
 impl Default for Editor {
@@ -141,28 +154,105 @@ impl Default for Editor {
             coloff: 0,
             screenrows: 0,
             screencols: 0,
-            numrows: 0,
-            rawmode: 0,
-            rows: Box::new(Vec::new()),
-            dirty: true,
-            filename: Box::new(None),
-            status: Box::new(Status::default()),
+            tab_width: DEFAULT_TAB_WIDTH,
+            rows: Rows::default(),
+            dirty: false,
+            filename: None,
+            status: Status::default(),
+            search: None,
+            prompt: None,
+            prompt_history: prompt::PromptHistory::new(),
+            quit_times: keymap::QUIT_TIMES,
+            history: History::default(),
+            highlighter: Highlighter::new(None, &Rows::default()),
+            mark: None,
+            kill_ring: clipboard::KillRing::default(),
+            system_clipboard: SystemClipboard::default(),
+            file_format: FileFormat::default(),
+            saved_file_format: FileFormat::default(),
+            disk: disk::DiskState::default(),
+            recovery: recovery::Recovery::default(),
+            timers: event_loop::Timers::default(),
+            loader: None,
+            lazy_load_min_size: lazy_load::MIN_SIZE,
         }
     }
 }
 
 impl Editor {
+    /// Opens `filename` for editing. If the file does not exist, the buffer
+    /// starts out empty and the file is created when it is saved.
     pub fn open(&mut self, filename: impl AsRef<Path>) -> KiroResult<()> {
-        self.filename = Box::new(Some(filename.as_ref().to_owned()));
-        let file = BufReader::new(File::open(filename)?);
-        for line in file.lines() {
-            self.append_line(line?);
+        self.filename = Some(filename.as_ref().to_owned());
+        self.load()?;
+        self.check_for_recovery_file();
+        Ok(())
+    }
+
+    /// Replaces the buffer with the contents of the file. Huge files are
+    /// loaded lazily (see `lazy_load`).
+    fn load(&mut self) -> KiroResult<()> {
+        // Changes made while reading are detected later on.
+        self.remember_disk_state();
+        self.loader = None;
+        let syntax = self.filename.as_deref().and_then(syntax::for_path);
+        let path = self.filename.as_ref().ok_or(Error::NoFileOpen)?;
+        let file = match File::open(path) {
+            Ok(file) => Some(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        match file {
+            Some(file) if file.metadata()?.len() >= self.lazy_load_min_size => {
+                self.highlighter = Highlighter::new(syntax, &Rows::default());
+                self.start_loading(&file)?;
+            }
+            file => {
+                let (rows, file_format) = match file {
+                    Some(file) => FileFormat::read(file)?,
+                    None => (Rows::default(), FileFormat::default()),
+                };
+                if rows.iter().any(|row| row.contains('\0')) {
+                    self.warn_about_binary_file();
+                }
+                self.rows = rows;
+                self.file_format = file_format;
+                self.saved_file_format = file_format;
+                self.highlighter = Highlighter::new(syntax, &self.rows);
+            }
         }
+        self.history = History::default();
+        self.clear_mark();
         self.dirty = false;
         Ok(())
     }
 
-    pub fn draw(&self) -> KiroResult<()> {
+    fn warn_about_binary_file(&mut self) {
+        self.set_status(format!(
+            "Warning: `{}` looks like a binary file (it contains NUL bytes)",
+            self.filename()
+        ));
+    }
+
+    /// Asks the terminal on `fd` for its size and adjusts the screen to it.
+    fn update_window_size(&mut self, fd: c_int) -> io::Result<()> {
+        let (mut rows, mut cols) = (0, 0);
+        if unsafe { getWindowSize(fd, libc::STDOUT_FILENO, &mut rows, &mut cols) } == -1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unable to query the screen for size (columns / rows)",
+            ));
+        }
+        // Two lines are needed for the status bar and message.
+        self.screenrows = rows.saturating_sub(2);
+        self.screencols = cols;
+        self.scroll();
+        Ok(())
+    }
+
+    pub fn draw(&mut self) -> KiroResult<()> {
+        self.highlighter
+            .highlight_up_to(&self.rows, self.rowoff + self.screenrows);
         let mut output = String::new();
         write!(output, "{}{}", ansi::HIDE_CURSOR, ansi::GOTO_TOP_LEFT)?;
 
@@ -191,16 +281,18 @@ impl Editor {
 
     fn render_welcome_message(&self) -> impl Iterator<Item = Cow<str>> {
         let msg = format!("キロ editor -- version {}", VERSION);
-        let render_width = render_width(&msg).unwrap_or_else(|| {
+        let render_width = unicode::render_width(&msg).unwrap_or_else(|| {
             panic!(
                 "Could not calculate render width of {:?} -- is the locale set up correctly?",
                 msg
             )
         });
+        // Wide characters and the leading `~` take more than one column each.
+        let extra_width = render_width - msg.chars().count() + 1;
         let greeting = format!(
             "~{:^width$}",
             msg,
-            width = self.screencols as usize - (render_width - msg.chars().count()) - 1,
+            width = (self.screencols as usize).saturating_sub(extra_width),
         );
         iter::repeat(Self::empty_line())
             .take(self.screenrows as usize / 3)
@@ -208,34 +300,119 @@ impl Editor {
     }
 
     fn render_buffer(&self) -> impl Iterator<Item = Cow<str>> {
-        self.screen_lines().map(move |line| {
-            crop_to(&line, self.coloff as _, self.screencols as _)
-                .to_string()
-                .into()
-        })
+        self.screen_lines()
+            .enumerate()
+            .map(move |(y, line)| self.render_line(self.rowoff + y, &line).into())
+    }
+
+    /// Renders the visible part of `line`, which is row `filerow` of the file.
+    /// Tabs are expanded to spaces, syntax is highlighted and the current
+    /// search match and the selection are shown in reverse video.
+    fn render_line(&self, filerow: usize, line: &str) -> String {
+        let start = self.coloff;
+        let end = self.coloff + self.screencols;
+        let search_match = self.search_match(filerow);
+        let selection = self.selection_in_row(filerow);
+        let hl = self.highlighter.highlight_row(&self.rows, filerow);
+        let mut rendered = String::new();
+        let mut current_color = Color::Default;
+        let mut reversed = false;
+        for cell in unicode::cells(line, self.tab_width) {
+            if cell.column + cell.width <= start {
+                continue;
+            }
+            if cell.column >= end {
+                break;
+            }
+            let escaped_byte = cell
+                .grapheme
+                .chars()
+                .next()
+                .and_then(raw_bytes::escaped_byte);
+            let color = match escaped_byte {
+                Some(_) => Color::Blue,
+                None => hl
+                    .as_ref()
+                    .map_or(Color::Default, |hl| hl[cell.offset].color()),
+            };
+            if color != current_color {
+                rendered.push_str(&ansi::foreground(color));
+                current_color = color;
+            }
+            let reverse = [&search_match, &selection]
+                .iter()
+                .any(|range| range.as_ref().map_or(false, |r| r.contains(&cell.offset)));
+            if reverse != reversed {
+                let escape = if reverse {
+                    ansi::REVERSE
+                }
+                else {
+                    ansi::NO_REVERSE
+                };
+                rendered.push_str(escape);
+                reversed = reverse;
+            }
+            let visible_width = (cell.column + cell.width).min(end) - cell.column.max(start);
+            if let Some(byte) = escaped_byte {
+                // Escapes cut off at the edge of the screen are shown partially.
+                let skipped = cell.column.max(start) - cell.column;
+                rendered.extend(
+                    raw_bytes::escape(byte)
+                        .chars()
+                        .skip(skipped)
+                        .take(visible_width),
+                );
+            }
+            else if cell.grapheme == "\t" || visible_width < cell.width {
+                // Tabs and double width characters cut off at the edge of the
+                // screen are replaced by spaces.
+                rendered.extend(iter::repeat(' ').take(visible_width));
+            }
+            else {
+                rendered.push_str(cell.grapheme);
+            }
+        }
+        if current_color != Color::Default || reversed {
+            rendered.push_str(ansi::RESET);
+        }
+        rendered
     }
 
     fn render_status_message(&self) -> String {
         let lstatus = format!(
-            "{} - {} lines {}",
+            "{} - {} lines {}{}",
             self.filename(),
             self.rows.len(),
-            if self.dirty { "(modified)" } else { "" },
+            self.loader().map_or_else(String::new, |loader| format!(
+                "(loading {}%) ",
+                loader.progress()
+            )),
+            match (self.dirty, self.disk.changed) {
+                (false, false) => "",
+                (true, false) => "(modified)",
+                (false, true) => "(changed on disk)",
+                (true, true) => "(modified, changed on disk)",
+            },
         );
-        let rstatus = format!("{}/{}", self.rowoff + self.cy + 1, self.rows.len(),);
+        let rstatus = format!(
+            "{} | {} | {}/{}",
+            self.highlighter
+                .syntax()
+                .map_or("no ft", |syntax| syntax.name),
+            self.file_format.name(),
+            self.cy + 1,
+            self.rows.len(),
+        );
+        // TODO: Correctly handle failing `render_width`
+        let status_width = unicode::render_width(&lstatus).unwrap_or_else(|| lstatus.len())
+            + unicode::render_width(&rstatus).unwrap_or_else(|| rstatus.len());
         let padding: String = iter::repeat(' ')
-            .take(
-                self.screencols as usize
-                    // TODO: Correctly handle failing `render_width`
-                    - render_width(&lstatus).unwrap_or_else(|| lstatus.len())
-                    - render_width(&rstatus).unwrap_or_else(|| rstatus.len()),
-            )
+            .take((self.screencols as usize).saturating_sub(status_width))
             .collect();
-        let statusmsg = if self.status.time.elapsed() <= STATUS_TIMEOUT {
-            &self.status.message
-        }
-        else {
-            ""
+        let statusmsg = match self.render_prompt() {
+            Some(prompt) => prompt.into(),
+            None if self.status.time.elapsed() < STATUS_TIMEOUT => Cow::from(&self.status.message),
+            None => "".into(),
         };
         format!(
             "{}{}{}{}{}{}{}{}{}",
@@ -254,7 +431,7 @@ impl Editor {
     fn screen_lines(&self) -> impl Iterator<Item = Cow<str>> {
         (0..std::cmp::min(self.screenrows, self.rows.len() - self.rowoff)).map(move |y| {
             let offset = self.rowoff + y;
-            (&self.rows[offset as usize]).into()
+            self.rows[offset as usize].into()
         })
     }
 
@@ -263,209 +440,378 @@ impl Editor {
     }
 
     fn goto_current_cursor_position(&self) -> String {
-        // TODO: Tabs and multibyte/double width characters
-        ansi::goto_position(self.cx as usize + 1, self.cy as usize + 1)
+        match self.prompt_cursor_column() {
+            Some(column) => ansi::goto_position(column + 1, self.screenrows + 2),
+            None => ansi::goto_position(self.rx() - self.coloff + 1, self.cy - self.rowoff + 1),
+        }
     }
 
     fn insert_line(&mut self, idx: usize, line: String) {
-        self.rows.insert(idx, line);
-        self.dirty = true;
+        self.apply_change(Change::InsertLine { row: idx, line });
     }
 
-    fn append_line(&mut self, line: impl Into<String>) {
-        self.rows.push(line.into());
+    fn insert_char(&mut self, c: char) {
+        let cursor_before = self.cursor();
+        for _ in self.rows.len()..=self.cy {
+            self.insert_line(self.rows.len(), String::new());
+        }
+        self.apply_change(Change::Insert {
+            row: self.cy,
+            col: self.cx,
+            text: c.into(),
+        });
+        self.set_cursor(self.cy, self.cx + c.len_utf8());
+        self.commit_edit(EditKind::Typing, cursor_before);
     }
 
-    fn insert_char(&mut self, c: char) {
-        let filerow = self.filerow();
-        let filecol = self.filecol();
-        for _ in self.rows.len()..=filerow {
-            self.append_line("");
+    /// Inserts `text`, which may contain newlines, at the cursor and moves the
+    /// cursor behind it.
+    fn insert_text(&mut self, text: &str) {
+        for _ in self.rows.len()..=self.cy {
+            self.insert_line(self.rows.len(), String::new());
         }
-        let row = &mut self.rows[filerow];
-        for _ in row.len()..filecol {
-            row.push(' ');
+        self.apply_change(Change::Insert {
+            row: self.cy,
+            col: self.cx,
+            text: text.into(),
+        });
+        match text.rfind('\n') {
+            Some(idx) =>
+                self.set_cursor(self.cy + text.matches('\n').count(), text.len() - idx - 1),
+            None => self.set_cursor(self.cy, self.cx + text.len()),
         }
-        row.insert(filecol, std::char::from_u32(c as _).expect("invalid char"));
-        if self.cx == self.screencols - 1 {
-            self.coloff += 1;
+    }
+
+    fn insert_newline(&mut self) {
+        let cursor_before = self.cursor();
+        if self.cy < self.rows.len() {
+            self.apply_change(Change::Insert {
+                row: self.cy,
+                col: self.cx,
+                text: "\n".into(),
+            });
         }
         else {
-            self.cx += 1;
+            self.insert_line(self.rows.len(), String::new());
         }
-        self.dirty = true;
+        self.set_cursor(self.cy + 1, 0);
+        self.commit_edit(EditKind::Other, cursor_before);
     }
 
-    fn insert_newline(&mut self) {
-        let filecol = self.filecol();
-        let filerow = self.filerow();
-        if let Some(row) = self.rows.get_mut(filerow) {
-            let cursor_position = filecol.min(row.len());
-            let end = row[cursor_position..].into();
-            row.replace_range(cursor_position.., "");
-            self.insert_line(filerow + 1, end);
+    fn delete_character(&mut self) {
+        let cursor_before = self.cursor();
+        if self.cy >= self.rows.len() || (self.cy == 0 && self.cx == 0) {
+            return;
+        }
+        if self.cx != 0 {
+            let row = &self.rows[self.cy];
+            let start = unicode::previous_boundary(row, self.cx);
+            let text = row[start..self.cx].into();
+            self.apply_change(Change::Delete {
+                row: self.cy,
+                col: start,
+                text,
+            });
+            self.set_cursor(self.cy, start);
         }
         else {
-            self.append_line("");
+            let filecol = self.rows[self.cy - 1].len();
+            self.apply_change(Change::Delete {
+                row: self.cy - 1,
+                col: filecol,
+                text: "\n".into(),
+            });
+            self.set_cursor(self.cy - 1, filecol);
+        }
+        self.commit_edit(EditKind::Other, cursor_before);
+    }
+
+    /// Deletes the character under the cursor, or joins the next line at the
+    /// end of a line.
+    fn delete_next_character(&mut self) {
+        let cursor_before = self.cursor();
+        let row = match self.rows.get(self.cy) {
+            Some(row) => row,
+            None => return,
+        };
+        let text = if self.cx < row.len() {
+            row[self.cx..unicode::next_boundary(row, self.cx)].into()
         }
-        if self.cy == self.screenrows - 1 {
-            self.rowoff += 1;
+        else if self.cy + 1 < self.rows.len() {
+            "\n".into()
         }
         else {
-            self.cy += 1;
+            return;
+        };
+        self.apply_change(Change::Delete {
+            row: self.cy,
+            col: self.cx,
+            text,
+        });
+        self.commit_edit(EditKind::Other, cursor_before);
+    }
+
+    /// Applies `change` to the buffer and records it in the undo history.
+    fn apply_change(&mut self, change: Change) {
+        self.apply_change_unrecorded(&change);
+        self.history.record(change);
+    }
+
+    /// Applies `change` to the buffer without recording it, e. g. when undoing
+    /// or redoing it.
+    fn apply_change_unrecorded(&mut self, change: &Change) {
+        change.apply(&mut self.rows);
+        self.highlighter.update(&self.rows, change);
+        self.clear_mark();
+    }
+
+    /// Finishes an edit started at `cursor_before`, making all changes
+    /// applied since the last call one undo step.
+    fn commit_edit(&mut self, kind: EditKind, cursor_before: Cursor) {
+        self.history.commit(kind, cursor_before, self.cursor());
+        self.buffer_changed();
+    }
+
+    /// Must be called whenever the buffer or its format changed.
+    fn buffer_changed(&mut self) {
+        self.dirty = self.history.is_modified() || self.file_format != self.saved_file_format;
+        self.schedule_recovery_file();
+    }
+
+    fn undo(&mut self) {
+        match self.history.undo() {
+            Some((changes, cursor)) => {
+                changes
+                    .iter()
+                    .for_each(|change| self.apply_change_unrecorded(change));
+                self.restore_cursor(cursor);
+            }
+            None => self.set_status("Nothing to undo".into()),
         }
-        self.cx = 0;
-        self.coloff = 0;
+        self.buffer_changed();
     }
 
-    fn delete_character(&mut self) {
-        let filerow = self.filerow();
-        let filecol = self.filecol();
-        if filerow == 0 && filecol == 0 {
-            return;
+    fn redo(&mut self) {
+        match self.history.redo() {
+            Some((changes, cursor)) => {
+                changes
+                    .iter()
+                    .for_each(|change| self.apply_change_unrecorded(change));
+                self.restore_cursor(cursor);
+            }
+            None => self.set_status("Nothing to redo".into()),
         }
-        if let Some(row) = self.rows.get_mut(filerow) {
-            if filecol != 0 {
-                row.remove(filecol - 1);
-                if self.cx == 0 && self.coloff != 0 {
-                    self.coloff -= 1;
+        self.buffer_changed();
+    }
+
+    fn move_cursor(&mut self, key: Key) {
+        let row = self.rows.get(self.cy).unwrap_or("");
+
+        match key {
+            Key::Left =>
+                if self.cx != 0 {
+                    self.set_cursor(self.cy, unicode::previous_boundary(row, self.cx));
                 }
-                else {
-                    self.cx -= 1;
+                else if self.cy > 0 {
+                    self.set_cursor(self.cy - 1, self.rows[self.cy - 1].len());
+                },
+            Key::Right =>
+                if self.cx < row.len() {
+                    self.set_cursor(self.cy, unicode::next_boundary(row, self.cx));
                 }
-            }
-            else {
-                let row = self.rows.remove(filerow);
-                let filecol = self.rows[filerow - 1].len();
-                self.rows[filerow - 1].push_str(&row);
-                if self.cy == 0 {
-                    self.rowoff -= 1;
+                else if self.cy < self.rows.len() {
+                    self.set_cursor(self.cy + 1, 0);
+                },
+            Key::Up =>
+                if self.cy > 0 {
+                    self.move_to_row(self.cy - 1);
+                },
+            Key::Down =>
+                if self.cy < self.rows.len() {
+                    self.move_to_row(self.cy + 1);
+                },
+            Key::PageUp => {
+                self.move_to_row(self.rowoff);
+                for _ in 0..self.screenrows {
+                    self.move_cursor(Key::Up);
                 }
-                else {
-                    self.cy -= 1;
+            }
+            Key::PageDown => {
+                let last_visible = self.rowoff + self.screenrows.saturating_sub(1);
+                self.move_to_row(last_visible.min(self.rows.len()));
+                for _ in 0..self.screenrows {
+                    self.move_cursor(Key::Down);
                 }
-                self.cx = filecol;
-                if self.cx >= self.screencols {
-                    let shift = self.screencols - self.cx + 1;
-                    self.cx -= shift;
-                    self.coloff += shift;
+            }
+            Key::Home => {
+                // "Smart home": go to the first non-blank character first, and
+                // to the start of the line only if already there.
+                let first_non_blank = row.len() - row.trim_start().len();
+                let filecol = if self.cx == first_non_blank {
+                    0
                 }
+                else {
+                    first_non_blank
+                };
+                self.set_cursor(self.cy, filecol);
             }
+            Key::End => self.set_cursor(self.cy, row.len()),
+            _ => unreachable!(),
         }
-        self.dirty = true;
     }
 
-    fn move_cursor(&mut self, key: KEY_ACTION) {
-        let filerow = self.rowoff + self.cy;
-        let filecol = self.coloff + self.cx;
+    /// Moves the cursor to the start of the previous word or the end of the
+    /// next word, continuing on the adjacent line at the start/end of a line.
+    fn move_cursor_by_word(&mut self, key: Key) {
+        let row = self.rows.get(self.cy).unwrap_or("");
 
         match key {
-            KEY_ACTION::ARROW_LEFT =>
-                if self.cx == 0 {
-                    if self.coloff != 0 {
-                        self.coloff -= 1;
-                    }
-                    else if filerow > 0 {
-                        self.cy -= 1;
-                        self.cx = self.rows[(filerow - 1) as usize].len() as _;
-                        if self.cx > self.screencols - 1 {
-                            self.coloff = self.cx - self.screencols + 1;
-                            self.cx = self.screencols - 1;
-                        }
-                    }
+            Key::Left =>
+                if self.cx != 0 {
+                    self.set_cursor(self.cy, unicode::previous_word_start(row, self.cx));
                 }
                 else {
-                    self.cx -= 1;
+                    self.move_cursor(Key::Left);
                 },
-            KEY_ACTION::ARROW_RIGHT => {
-                if filerow < self.rows.len() && filecol < self.rows[filerow].len() {
-                    if self.cx == self.screencols - 1 {
-                        self.coloff += 1;
-                    }
-                    else {
-                        self.cx += 1;
-                    }
-                }
-                else if filerow < self.rows.len() && filecol == self.rows[filerow].len() {
-                    self.cx = 0;
-                    self.coloff = 0;
-                    if self.cy == self.screenrows - 1 {
-                        self.rowoff += 1;
-                    }
-                    else {
-                        self.cy += 1;
-                    }
-                }
-            }
-            KEY_ACTION::ARROW_UP =>
-                if self.cy == 0 {
-                    if self.rowoff != 0 {
-                        self.rowoff -= 1;
-                    }
+            Key::Right =>
+                if self.cx < row.len() {
+                    self.set_cursor(self.cy, unicode::next_word_end(row, self.cx));
                 }
                 else {
-                    self.cy -= 1;
-                },
-            KEY_ACTION::ARROW_DOWN =>
-                if filerow < self.rows.len() {
-                    if self.cy == self.screenrows - 1 {
-                        self.rowoff += 1;
-                    }
-                    else {
-                        self.cy += 1;
-                    }
+                    self.move_cursor(Key::Right);
                 },
             _ => unreachable!(),
         }
-        let filerow = self.rowoff + self.cy;
-        let filecol = self.coloff + self.cx;
-        let rowlen = self.rows.get(filerow).map_or(0, String::len);
-        if filecol > rowlen {
-            self.coloff = std::cmp::min(self.coloff, rowlen);
-            self.cx = rowlen - self.coloff;
+    }
+
+    fn move_to_buffer_start(&mut self) {
+        self.set_cursor(0, 0);
+    }
+
+    fn move_to_buffer_end(&mut self) {
+        self.wait_for_loading();
+        match self.rows.last() {
+            Some(row) => self.set_cursor(self.rows.len() - 1, row.len()),
+            None => self.set_cursor(0, 0),
         }
     }
 
-    fn save(&mut self) -> KiroResult<u64> {
-        let path = std::fs::canonicalize((&*self.filename).as_ref().ok_or(Error::NoFileOpen)?)?;
-        let file_name = {
-            let mut file_name = path.file_name().unwrap().to_os_string();
-            file_name.push("~kirosave");
-            file_name
-        };
+    /// Moves the cursor vertically to `filerow`, keeping it in the same
+    /// display column if possible.
+    fn move_to_row(&mut self, filerow: usize) {
+        let rx = self.rx();
+        let filecol = self
+            .rows
+            .get(filerow)
+            .map_or(0, |row| unicode::offset_at_column(row, rx, self.tab_width));
+        self.set_cursor(filerow, filecol);
+    }
+
+    /// Writes the buffer to its file. Returns the number of bytes written.
+    pub fn save(&mut self) -> KiroResult<u64> {
+        self.wait_for_loading();
+        let path = self.filename.as_ref().ok_or(Error::NoFileOpen)?;
+        if save::overwrites_in_place(path)? {
+            // The mapping would change while it is written.
+            self.rows.copy_mapping();
+        }
+        let (rows, file_format) = (&self.rows, self.file_format);
+        let bytes_written = save::write_file(path, |file| file_format.write(rows, file))?;
 
-        let temp_file_path = {
-            let mut path = path.clone();
-            path.set_file_name(file_name);
-            path
-        };
-        let bytes_written = {
-            let mut file = File::create(temp_file_path.clone())?;
-            for row in &*self.rows {
-                writeln!(file, "{}", row)?;
+        self.history.mark_saved();
+        self.saved_file_format = self.file_format;
+        self.remember_disk_state();
+        self.remove_recovery_file();
+        self.dirty = false;
+        Ok(bytes_written)
+    }
+
+    /// Saves the buffer and reports the result in the status line.
+    fn save_and_report(&mut self) {
+        match self.save() {
+            Ok(bytes_written) =>
+                self.set_status(format!("{} bytes written to disk", bytes_written)),
+            Err(err) => {
+                self.set_status(format!(
+                    "Could not write to file `{}`: {:?}",
+                    self.filename(),
+                    err
+                ));
             }
-            file.metadata()?.len()
         };
-        rename(temp_file_path, path)?;
+    }
 
-        self.dirty = false;
-        Ok(bytes_written)
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            cx: self.cx,
+            cy: self.cy,
+            rowoff: self.rowoff,
+            coloff: self.coloff,
+        }
     }
 
-    pub fn set_status(&mut self, message: String) {
-        self.status = Box::new(Status::new(message));
+    fn restore_cursor(&mut self, cursor: Cursor) {
+        self.cx = cursor.cx;
+        self.cy = cursor.cy;
+        self.rowoff = cursor.rowoff;
+        self.coloff = cursor.coloff;
+        self.scroll();
     }
 
-    fn filerow(&self) -> usize {
-        self.rowoff + self.cy
+    /// Moves the cursor to `filerow`/`filecol`, scrolling the screen if the
+    /// target is not visible. `filecol` must be a grapheme cluster boundary.
+    fn set_cursor(&mut self, filerow: usize, filecol: usize) {
+        self.cy = filerow;
+        self.cx = filecol;
+        self.scroll();
     }
 
-    fn filecol(&self) -> usize {
-        self.coloff + self.cx
+    /// Adjusts `rowoff` and `coloff` so that the cursor is on screen.
+    fn scroll(&mut self) {
+        // Even if the screen is too small to show anything, the cursor must
+        // not end up before the first visible row or column.
+        let screenrows = self.screenrows.max(1);
+        if self.cy < self.rowoff {
+            self.rowoff = self.cy;
+        }
+        if self.cy >= self.rowoff + screenrows {
+            self.rowoff = self.cy + 1 - screenrows;
+        }
+        let rx = self.rx();
+        if rx < self.coloff {
+            self.coloff = rx;
+        }
+        // The whole grapheme cluster under the cursor has to be visible.
+        let cursor_width = self
+            .rows
+            .get(self.cy)
+            .and_then(|row| unicode::cells(row, self.tab_width).find(|cell| cell.offset == self.cx))
+            .map_or(1, |cell| cell.width.max(1));
+        if rx + cursor_width > self.coloff + self.screencols {
+            self.coloff = (rx + cursor_width).saturating_sub(self.screencols).min(rx);
+        }
+    }
+
+    /// The display column of the cursor.
+    fn rx(&self) -> usize {
+        self.rows.get(self.cy).map_or(0, |row| {
+            unicode::display_column(row, self.cx, self.tab_width)
+        })
+    }
+
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
+        self.scroll();
+    }
+
+    pub fn set_status(&mut self, message: String) {
+        self.status = Status::new(message);
+        self.timers
+            .set(Timer::StatusMessage, self.status.time + STATUS_TIMEOUT);
     }
 
     fn filename(&self) -> std::path::Display {
-        (&*self.filename)
+        self.filename
             .as_ref()
             .map_or_else(|| Path::new("<<new file>>"), PathBuf::as_ref)
             .display()
@@ -476,6 +822,14 @@ impl Editor {
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+struct Cursor {
+    cx: usize,
+    cy: usize,
+    rowoff: usize,
+    coloff: usize,
+}
+
 struct Status {
     message: String,
     time: Instant,
@@ -496,47 +850,6 @@ impl Default for Status {
     }
 }
 
-fn crop_to(s: &str, start: usize, width: usize) -> &str {
-    let mut indices = s.chars().scan((0, 0), |(pos, byte_idx), c| {
-        let result = Some((*pos, *byte_idx));
-        *pos += char_width(c).unwrap();
-        *byte_idx += c.len_utf8();
-        result
-    });
-    let start = indices.find(|&(pos, _)| pos >= start).map(|(_, i)| i);
-    let end = start.and_then(|start| {
-        indices
-            .find(|&(pos, _)| pos >= start + width)
-            .map(|(_, i)| i)
-    });
-    match (start, end) {
-        (Some(start), Some(end)) => &s[start..end],
-        (Some(start), _) => &s[start..],
-        (_, Some(end)) => &s[..end],
-        _ => "",
-    }
-}
-
-fn char_width(c: char) -> Option<usize> {
-    if c == '\t' {
-        // TODO: Move to 4-space tabs
-        return Some(8);
-    }
-    let len = unsafe { wcwidth(c as _) };
-    if len < 0 {
-        None
-    }
-    else {
-        Some(len as usize)
-    }
-}
-
-fn render_width(s: &str) -> Option<usize> {
-    s.chars()
-        .map(char_width)
-        .fold(Some(0), |acc, maybe_len| Some(acc? + maybe_len?))
-}
-
 struct When<It: Iterator> {
     iter: It,
 }
@@ -574,88 +887,3 @@ where
 {
     if_true.when(b).chain(if_false.when(!b))
 }
-
-fn instance() -> &'static mut Editor {
-    unsafe { &mut E }
-}
-
-#[no_mangle]
-pub extern "C" fn editorRefreshScreen() {
-    instance().draw().unwrap()
-}
-
-#[no_mangle]
-pub extern "C" fn editorClearStatusMessage() {
-    instance().set_status(String::new());
-}
-
-/// # Safety
-///
-/// `error` must be a null-terminated string.
-#[no_mangle]
-pub unsafe extern "C" fn editorSetStatusMessageSearch(query: *const c_char) {
-    if query.is_null() {
-        return;
-    }
-    instance().set_status(format!(
-        "Search: {} (Use Esc/Arrows/Return)",
-        CStr::from_ptr(query).to_string_lossy()
-    ));
-}
-
-#[no_mangle]
-pub extern "C" fn editorSetStatusMessageQuit(count: c_int) {
-    instance().set_status(format!(
-        "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
-        count
-    ));
-}
-
-/// # Safety
-///
-/// `line` must be a null-terminated string. It is safe to pass `NULL` for
-/// `line`, but this crashes the editor.
-#[no_mangle]
-pub unsafe extern "C" fn editorInsertRow(
-    _numrows: c_int,
-    line: *const c_char,
-    _len: libc::ssize_t,
-) {
-    assert!(!line.is_null());
-    instance().append_line(CStr::from_ptr(line).to_string_lossy());
-}
-
-#[no_mangle]
-pub extern "C" fn editorInsertNewline() {
-    instance().insert_newline();
-}
-
-#[no_mangle]
-pub extern "C" fn editorSave() {
-    match instance().save() {
-        Ok(bytes_written) =>
-            instance().set_status(format!("{} bytes written to disk", bytes_written)),
-        Err(err) => {
-            instance().set_status(format!(
-                "Could not write to file `{}`: {:?}",
-                instance().filename(),
-                err
-            ));
-        }
-    };
-}
-
-#[no_mangle]
-pub extern "C" fn editorDelChar() {
-    instance().delete_character();
-}
-
-#[no_mangle]
-pub extern "C" fn editorMoveCursor(key: KEY_ACTION) {
-    instance().move_cursor(key);
-}
-
-#[no_mangle]
-pub extern "C" fn editorInsertChar(c: c_int) {
-    instance().insert_char(char::from_u32(c as _).expect("invalid char"));
-}
@@ -1,62 +1,46 @@
-use std::{
-    ffi::CString,
-    io::{
-        self,
-        Write,
-    },
-    mem,
-};
-
-use libc::c_int;
+use std::ffi::CString;
 
 use kiro::{
+    terminal::{
+        self,
+        Terminal,
+    },
     Editor,
     KiroResult,
 };
 
-#[link(name = "kilo", kind = "static")]
-extern "C" {
-    fn enableRawMode(fd: c_int);
-    fn editorProcessKeypress(fd: c_int);
-    fn updateWindowSize();
-    fn handleSigWinCh(_: c_int);
-
-    // FIXME: This warning is a bug, see https://github.com/rust-lang/rust/pull/72700 and
-    // https://github.com/rust-lang/rust/pull/74448
-    #[allow(improper_ctypes)]
-    static mut E: Editor;
-}
-
-extern "C" fn restore_primary_buffer() {
-    print!("{}", kiro::ansi::PRIMARY_BUFFER);
-    io::stdout().lock().flush().unwrap();
-}
-
 fn main() -> KiroResult<()> {
-    let filename = std::env::args()
-        .nth(1)
-        .ok_or(kiro::Error::IncorrectInvocation)?;
+    let filename = std::env::args().nth(1);
 
-    println!("{}", kiro::ansi::ALTERNATIVE_BUFFER);
+    let locale = CString::new("")?;
     unsafe {
-        let locale = CString::new("")?;
         libc::setlocale(libc::LC_CTYPE, locale.as_ptr() as _);
-        // XXX: `E` is statically allocated on the C side, so it is not correctly
-        // initialised on the Rust side. This means we cannot run the
-        // destructor.
-        mem::forget(mem::take(&mut E));
-        updateWindowSize();
-        let result = libc::signal(libc::SIGWINCH, handleSigWinCh as _);
-        if result == libc::SIG_ERR {
-            return Err(kiro::Error::IoError(io::Error::last_os_error()));
-        }
-        libc::atexit(restore_primary_buffer);
-        E.open(filename)?;
-        enableRawMode(libc::STDIN_FILENO);
-        E.set_status(kiro::HELP_MESSAGE.into());
-        loop {
-            E.draw()?;
-            editorProcessKeypress(libc::STDIN_FILENO);
-        }
     }
+
+    let mut editor = Editor::default();
+    if let Some(tab_width) = std::env::var("KIRO_TAB_WIDTH")
+        .ok()
+        .and_then(|tab_width| tab_width.parse().ok())
+    {
+        editor.set_tab_width(tab_width);
+    }
+    if let Some(system_clipboard) = std::env::var("KIRO_OSC52")
+        .ok()
+        .and_then(|system_clipboard| system_clipboard.parse().ok())
+    {
+        editor.set_system_clipboard(system_clipboard);
+    }
+    // Opening the file may replace the help with a warning.
+    editor.set_status(kiro::HELP_MESSAGE.into());
+    if let Some(filename) = filename {
+        editor.open(filename)?;
+    }
+    let terminal = Terminal::enter(libc::STDIN_FILENO)?;
+    let result = editor.run(libc::STDIN_FILENO);
+    // Errors are printed on the normal screen.
+    drop(terminal);
+    if let Err(kiro::Error::Signal(signal)) = result {
+        terminal::exit_by_signal(signal);
+    }
+    result
 }
@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    fs,
+    mem,
+    path::PathBuf,
+};
+
+use crate::{
+    input::{
+        Key,
+        KeyEvent,
+        Modifiers,
+    },
+    syntax::{
+        self,
+        Highlighter,
+    },
+    unicode,
+    Editor,
+};
+
+/// The different uses of the prompt. Each of them has its own history.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PromptKind {
+    Search,
+    SaveAs,
+    GotoLine,
+    DiskChange,
+    Recovery,
+    Overwrite,
+}
+
+pub(crate) type PromptHistory = HashMap<PromptKind, Vec<String>>;
+
+/// Callbacks that give a prompt its behaviour.
+#[derive(Clone, Copy)]
+pub(crate) struct Hooks {
+    /// Called with every key before the prompt handles it. Returns whether
+    /// the key was consumed.
+    pub(crate) on_key: fn(&mut Editor, KeyEvent) -> bool,
+    /// Called whenever the input changed.
+    pub(crate) on_change: fn(&mut Editor, &str),
+    /// Checks the input before it is confirmed. An error keeps the prompt open
+    /// and is shown next to the input.
+    pub(crate) validate: fn(&Editor, &str) -> Result<(), String>,
+    pub(crate) on_confirm: fn(&mut Editor, String),
+    pub(crate) on_cancel: fn(&mut Editor),
+}
+
+impl Default for Hooks {
+    fn default() -> Hooks {
+        Hooks {
+            on_key: |_, _| false,
+            on_change: |_, _| (),
+            validate: |_, _| Ok(()),
+            on_confirm: |_, _| (),
+            on_cancel: |_| (),
+        }
+    }
+}
+
+/// A line of input that is being entered in the status line.
+pub(crate) struct Prompt {
+    kind: PromptKind,
+    message: &'static str,
+    help: &'static str,
+    input: String,
+    /// Byte offset of the cursor in `input`.
+    cursor: usize,
+    /// The history entry that is being shown, together with the input that was
+    /// entered before browsing the history.
+    history_position: Option<(usize, String)>,
+    error: Option<String>,
+    hooks: Hooks,
+}
+
+impl Prompt {
+    fn render(&self) -> String {
+        format!(
+            "{}: {} {}",
+            self.message,
+            self.input,
+            self.error.as_deref().unwrap_or(self.help),
+        )
+    }
+
+    /// Returns the display column of the cursor in the status line.
+    fn cursor_column(&self) -> usize {
+        let prefix = format!("{}: {}", self.message, &self.input[..self.cursor]);
+        unicode::display_column(&prefix, prefix.len(), 1)
+    }
+
+    fn set_input(&mut self, input: String) {
+        self.cursor = input.len();
+        self.input = input;
+    }
+}
+
+impl Editor {
+    /// Asks the user for a line of input. `hooks` decide what happens with it.
+    pub(crate) fn start_prompt(
+        &mut self,
+        kind: PromptKind,
+        message: &'static str,
+        help: &'static str,
+        initial: String,
+        hooks: Hooks,
+    ) {
+        self.prompt = Some(Prompt {
+            kind,
+            message,
+            help,
+            cursor: initial.len(),
+            input: initial,
+            history_position: None,
+            error: None,
+            hooks,
+        });
+    }
+
+    pub(crate) fn close_prompt(&mut self) {
+        self.prompt = None;
+        self.set_status(String::new());
+    }
+
+    pub(crate) fn is_prompting(&self) -> bool {
+        self.prompt.is_some()
+    }
+
+    pub(crate) fn process_prompt_keypress(&mut self, key: KeyEvent) {
+        const CTRL_H: KeyEvent = KeyEvent::ctrl('h');
+        const CTRL_N: KeyEvent = KeyEvent::ctrl('n');
+        const CTRL_P: KeyEvent = KeyEvent::ctrl('p');
+        const CTRL_U: KeyEvent = KeyEvent::ctrl('u');
+
+        let hooks = match &mut self.prompt {
+            Some(prompt) => {
+                prompt.error = None;
+                prompt.hooks
+            }
+            None => return,
+        };
+        if (hooks.on_key)(self, key) {
+            return;
+        }
+        let prompt = match &mut self.prompt {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        let input_before = prompt.input.clone();
+        match key {
+            KeyEvent { key: Key::Enter, .. } => {
+                self.confirm_prompt();
+                return;
+            }
+            KeyEvent { key: Key::Esc, .. } => {
+                self.close_prompt();
+                (hooks.on_cancel)(self);
+                return;
+            }
+            KeyEvent { key: Key::Backspace, .. } | CTRL_H =>
+                if prompt.cursor > 0 {
+                    let start = unicode::previous_boundary(&prompt.input, prompt.cursor);
+                    prompt.input.replace_range(start..prompt.cursor, "");
+                    prompt.cursor = start;
+                },
+            KeyEvent { key: Key::Delete, .. } =>
+                if prompt.cursor < prompt.input.len() {
+                    let end = unicode::next_boundary(&prompt.input, prompt.cursor);
+                    prompt.input.replace_range(prompt.cursor..end, "");
+                },
+            CTRL_U => prompt.set_input(String::new()),
+            KeyEvent { key: Key::Left, .. } =>
+                prompt.cursor = unicode::previous_boundary(&prompt.input, prompt.cursor),
+            KeyEvent { key: Key::Right, .. } =>
+                if prompt.cursor < prompt.input.len() {
+                    prompt.cursor = unicode::next_boundary(&prompt.input, prompt.cursor);
+                },
+            KeyEvent { key: Key::Home, .. } => prompt.cursor = 0,
+            KeyEvent { key: Key::End, .. } => prompt.cursor = prompt.input.len(),
+            KeyEvent { key: Key::Up, .. } | CTRL_P => self.browse_prompt_history(true),
+            KeyEvent { key: Key::Down, .. } | CTRL_N => self.browse_prompt_history(false),
+            KeyEvent {
+                key: Key::Char(c),
+                modifiers: Modifiers::NONE,
+            } if !c.is_control() => {
+                prompt.input.insert(prompt.cursor, c);
+                prompt.cursor += c.len_utf8();
+            }
+            _ => (),
+        }
+        if let Some(prompt) = &self.prompt {
+            if prompt.input != input_before {
+                let input = prompt.input.clone();
+                (hooks.on_change)(self, &input);
+            }
+        }
+    }
+
+    /// Inserts the first line of pasted `text` at the cursor of the prompt.
+    pub(crate) fn paste_into_prompt(&mut self, text: &str) {
+        let prompt = match &mut self.prompt {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        let line: String = text
+            .lines()
+            .next()
+            .unwrap_or("")
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect();
+        if line.is_empty() {
+            return;
+        }
+        prompt.input.insert_str(prompt.cursor, &line);
+        prompt.cursor += line.len();
+        prompt.error = None;
+        let (on_change, input) = (prompt.hooks.on_change, prompt.input.clone());
+        on_change(self, &input);
+    }
+
+    /// Returns what to show in the message line while prompting.
+    pub(crate) fn render_prompt(&self) -> Option<String> {
+        self.prompt.as_ref().map(Prompt::render)
+    }
+
+    /// Returns the display column of the cursor in the message line while
+    /// prompting.
+    pub(crate) fn prompt_cursor_column(&self) -> Option<usize> {
+        self.prompt.as_ref().map(Prompt::cursor_column)
+    }
+
+    fn confirm_prompt(&mut self) {
+        let prompt = self.prompt.as_ref().unwrap();
+        if let Err(error) = (prompt.hooks.validate)(self, &prompt.input) {
+            self.prompt.as_mut().unwrap().error = Some(error);
+            return;
+        }
+        let prompt = self.prompt.take().unwrap();
+        let history = self.prompt_history.entry(prompt.kind).or_default();
+        if !prompt.input.is_empty() && history.last() != Some(&prompt.input) {
+            history.push(prompt.input.clone());
+        }
+        self.set_status(String::new());
+        (prompt.hooks.on_confirm)(self, prompt.input);
+    }
+
+    /// Replaces the input with the previous (`backwards`) or next entry in the
+    /// history of the current prompt kind.
+    fn browse_prompt_history(&mut self, backwards: bool) {
+        let prompt = self.prompt.as_mut().unwrap();
+        let history = match self.prompt_history.get(&prompt.kind) {
+            Some(history) if !history.is_empty() => history,
+            _ => return,
+        };
+        let position = match (prompt.history_position.take(), backwards) {
+            (None, true) => Some((history.len() - 1, prompt.input.clone())),
+            (None, false) => None,
+            (Some((idx, draft)), true) => Some((idx.saturating_sub(1), draft)),
+            (Some((idx, draft)), false) if idx + 1 < history.len() => Some((idx + 1, draft)),
+            (Some((_, draft)), false) => {
+                prompt.set_input(draft);
+                None
+            }
+        };
+        if let Some((idx, _)) = position {
+            prompt.set_input(history[idx].clone());
+        }
+        prompt.history_position = position;
+    }
+
+    pub(crate) fn start_save_as(&mut self) {
+        let initial = self
+            .filename
+            .as_ref()
+            .map_or_else(String::new, |path| path.display().to_string());
+        self.prompt_for_file_name(initial);
+    }
+
+    fn prompt_for_file_name(&mut self, initial: String) {
+        let hooks = Hooks {
+            validate: |_, input| {
+                if input.is_empty() {
+                    Err("(File name must not be empty)".into())
+                }
+                else if fs::metadata(input).map_or(false, |metadata| metadata.is_dir()) {
+                    Err("(Is a directory)".into())
+                }
+                else {
+                    Ok(())
+                }
+            },
+            on_confirm: |editor, input| {
+                let path = PathBuf::from(input);
+                if path.exists() && editor.filename.as_ref() != Some(&path) {
+                    editor.confirm_overwrite(path);
+                }
+                else {
+                    editor.save_as(path);
+                }
+            },
+            on_cancel: |editor| editor.set_status("Save aborted".into()),
+            ..Hooks::default()
+        };
+        self.start_prompt(
+            PromptKind::SaveAs,
+            "Save as",
+            "(ESC to cancel)",
+            initial,
+            hooks,
+        );
+    }
+
+    /// Asks whether the existing file at `path` should be overwritten.
+    fn confirm_overwrite(&mut self, path: PathBuf) {
+        let hooks = Hooks {
+            on_key: |editor, key| {
+                match key {
+                    KeyEvent { key: Key::Esc, .. } => return false,
+                    KeyEvent { key: Key::Char('y'), .. } => {
+                        let path = editor.prompt.take().unwrap().input;
+                        editor.save_as(path.into());
+                    }
+                    KeyEvent { key: Key::Char('n'), .. } => {
+                        let path = editor.prompt.take().unwrap().input;
+                        editor.prompt_for_file_name(path);
+                    }
+                    _ => (),
+                }
+                true
+            },
+            on_cancel: |editor| editor.set_status("Save aborted".into()),
+            ..Hooks::default()
+        };
+        self.start_prompt(
+            PromptKind::Overwrite,
+            "File exists, overwrite",
+            "(y = yes, n = choose another name, ESC to cancel)",
+            path.display().to_string(),
+            hooks,
+        );
+    }
+
+    /// Saves the buffer to `path`, which becomes its file name only if that
+    /// worked.
+    fn save_as(&mut self, path: PathBuf) {
+        let old_filename = self.filename.replace(path);
+        match self.save() {
+            Ok(bytes_written) => {
+                // The recovery file belongs to the old name.
+                let filename = mem::replace(&mut self.filename, old_filename);
+                self.remove_recovery_file();
+                self.filename = filename;
+                let syntax = self.filename.as_deref().and_then(syntax::for_path);
+                self.highlighter = Highlighter::new(syntax, &self.rows);
+                self.set_status(format!("{} bytes written to disk", bytes_written));
+            }
+            Err(err) => {
+                let path = mem::replace(&mut self.filename, old_filename).unwrap();
+                self.set_status(format!(
+                    "Could not write to file `{}`: {:?}",
+                    path.display(),
+                    err
+                ));
+            }
+        }
+    }
+
+    pub(crate) fn start_goto_line(&mut self) {
+        let hooks = Hooks {
+            on_key: |editor, key| {
+                if let KeyEvent { key: Key::Enter, .. } = key {
+                    let line = editor
+                        .prompt
+                        .as_ref()
+                        .and_then(|prompt| prompt.input.parse::<usize>().ok());
+                    if line.map_or(false, |line| line > editor.rows.len()) {
+                        // The line may not have been found yet.
+                        editor.wait_for_loading();
+                    }
+                }
+                false
+            },
+            validate: |editor, input| {
+                let last_line = editor.rows.len().max(1);
+                match input.parse::<usize>() {
+                    Ok(line) if (1..=last_line).contains(&line) => Ok(()),
+                    _ => Err(format!("(Enter a line between 1 and {})", last_line)),
+                }
+            },
+            on_confirm: |editor, input| {
+                let line: usize = input.parse().unwrap();
+                editor.set_cursor(line - 1, 0);
+            },
+            ..Hooks::default()
+        };
+        self.start_prompt(
+            PromptKind::GotoLine,
+            "Go to line",
+            "(ESC to cancel)",
+            String::new(),
+            hooks,
+        );
+    }
+}
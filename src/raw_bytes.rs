@@ -0,0 +1,212 @@
+//! Lossless handling of files that are not valid UTF-8.
+//!
+//! Every byte that is not part of a valid UTF-8 sequence is stored in the
+//! buffer as one of the otherwise unused code points U+10FF80 to U+10FFFF and
+//! turned back into the original byte when the file is saved. Valid characters
+//! from that range are stored byte by byte like invalid ones, so that every
+//! file round-trips unchanged.
+
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    ops::RangeInclusive,
+    str,
+};
+
+const RAW_BYTES: RangeInclusive<char> = '\u{10FF80}'..='\u{10FFFF}';
+const RAW_BYTE_OFFSET: u32 = 0x10_FF00;
+
+/// Converts the contents of a file to a string, replacing bytes that are not
+/// valid UTF-8 by raw byte characters. Valid UTF-8 is taken over without
+/// copying it.
+pub(crate) fn decode(bytes: Vec<u8>) -> String {
+    if is_plain(&bytes) {
+        String::from_utf8(bytes).unwrap()
+    }
+    else {
+        decode_slice(&bytes)
+    }
+}
+
+/// Tells whether `bytes` decode to the same bytes, so that they can be used as
+/// a string as they are.
+pub(crate) fn is_plain(bytes: &[u8]) -> bool {
+    match str::from_utf8(bytes) {
+        // Characters from `RAW_BYTES` start with 0xF4, which is rare.
+        Ok(s) => !bytes.contains(&0xF4) || !s.chars().any(|c| RAW_BYTES.contains(&c)),
+        Err(_) => false,
+    }
+}
+
+fn decode_slice(mut bytes: &[u8]) -> String {
+    let mut decoded = String::with_capacity(bytes.len());
+    loop {
+        let (valid, invalid) = match str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                bytes = &rest[invalid_len..];
+                (str::from_utf8(valid).unwrap(), &rest[..invalid_len])
+            }
+        };
+        for c in valid.chars() {
+            if RAW_BYTES.contains(&c) {
+                push_raw_bytes(&mut decoded, c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            else {
+                decoded.push(c);
+            }
+        }
+        push_raw_bytes(&mut decoded, invalid);
+        if invalid.is_empty() {
+            return decoded;
+        }
+    }
+}
+
+/// Converts `s` back to the bytes it was decoded from.
+pub(crate) fn encode(s: &str) -> Cow<[u8]> {
+    if !s.chars().any(|c| RAW_BYTES.contains(&c)) {
+        return s.as_bytes().into();
+    }
+    let mut encoded = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match raw_byte(c) {
+            Some(byte) => encoded.push(byte),
+            None => encoded.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    encoded.into()
+}
+
+/// Returns the byte that should be displayed as an escape like `<FF>` instead
+/// of `c`: raw bytes and control characters other than tab.
+pub(crate) fn escaped_byte(c: char) -> Option<u8> {
+    match c {
+        '\t' => None,
+        _ if c.is_ascii_control() => Some(c as u8),
+        _ => raw_byte(c),
+    }
+}
+
+/// The visible escape of `byte`.
+pub(crate) fn escape(byte: u8) -> String {
+    format!("<{:02X}>", byte)
+}
+
+fn raw_byte(c: char) -> Option<u8> {
+    if RAW_BYTES.contains(&c) {
+        Some(u8::try_from(c as u32 - RAW_BYTE_OFFSET).unwrap())
+    }
+    else {
+        None
+    }
+}
+
+/// The character that stands for `byte` when it is not valid UTF-8.
+pub(crate) fn raw_char(byte: u8) -> char {
+    char::from_u32(RAW_BYTE_OFFSET + u32::from(byte)).unwrap()
+}
+
+fn push_raw_bytes(s: &mut String, bytes: &[u8]) {
+    s.extend(bytes.iter().map(|&byte| raw_char(byte)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file_format::FileFormat,
+        test_util::Rng,
+        unicode,
+        Editor,
+    };
+
+    fn round_trip(bytes: &[u8]) -> Vec<u8> {
+        let (rows, file_format) = FileFormat::read(bytes).unwrap();
+        let mut written = vec![];
+        file_format.write(&rows, &mut written).unwrap();
+        written
+    }
+
+    fn raw(bytes: &[u8]) -> String {
+        bytes.iter().map(|&byte| raw_char(byte)).collect()
+    }
+
+    #[test]
+    fn invalid_bytes_are_decoded_one_by_one() {
+        for bytes in &[
+            &b"\xFF"[..],
+            // Truncated sequences.
+            b"\xE3\x82",
+            b"\xF0\x9F\xA6",
+            // An encoded surrogate, an overlong encoding and a code point above
+            // U+10FFFF.
+            b"\xED\xA0\x80",
+            b"\xC0\xAF",
+            b"\xF4\x90\x80\x80",
+            // Valid encodings of U+10FF80 and U+10FFFF, which stand for raw
+            // bytes themselves.
+            b"\xF4\x8F\xBE\x80",
+            b"\xF4\x8F\xBF\xBF",
+        ] {
+            assert!(!is_plain(bytes));
+            assert_eq!(decode(bytes.to_vec()), raw(bytes));
+            assert_eq!(encode(&raw(bytes)), *bytes);
+            assert_eq!(round_trip(bytes), *bytes);
+        }
+    }
+
+    #[test]
+    fn valid_text_is_kept() {
+        let text = "aä\u{10FF7F}\u{FFFD}キ🦀\r\n";
+        assert!(is_plain(text.as_bytes()));
+        assert_eq!(decode(text.into()), text);
+        assert_eq!(encode(text), text.as_bytes());
+
+        let mut mixed = b"a\xFF".to_vec();
+        mixed.extend_from_slice(text.as_bytes());
+        assert_eq!(
+            decode(mixed.clone()),
+            format!("a{}{}", raw_char(0xFF), text)
+        );
+        assert_eq!(encode(&decode(mixed.clone())), mixed);
+    }
+
+    #[test]
+    fn random_bytes_round_trip() {
+        // Bytes that start, continue or end sequences, line endings and the
+        // encoding of U+10FF80.
+        const BYTES: &[u8] =
+            b"a\r\n\0\x7F\x80\x8F\x90\xA0\xBE\xBF\xC0\xC3\xE3\xED\xEF\xF0\xF4\xF5\xFF";
+        let mut rng = Rng::new(17);
+        for _ in 0..2000 {
+            let len = rng.below(40);
+            let bytes: Vec<u8> = (0..len).map(|_| *rng.pick(BYTES)).collect();
+            assert_eq!(encode(&decode(bytes.clone())), bytes);
+            assert_eq!(round_trip(&bytes), bytes);
+        }
+    }
+
+    #[test]
+    fn raw_bytes_are_shown_as_escapes() {
+        assert_eq!(escape(0xFF), "<FF>");
+        assert_eq!(escaped_byte(raw_char(0x80)), Some(0x80));
+        assert_eq!(escaped_byte('\x1B'), Some(0x1B));
+        assert_eq!(escaped_byte('\t'), None);
+        assert_eq!(escaped_byte('\u{10FF7F}'), None);
+        assert_eq!(unicode::grapheme_width(&raw(b"\xFF")), 4);
+
+        let editor = Editor { screencols: 80, ..Editor::default() };
+        let mut line = "a".to_owned();
+        line.push(raw_char(0xFF));
+        line.push('\u{1}');
+        let rendered = editor.render_line(0, &line);
+        assert!(rendered.contains("<FF><01>"));
+        assert!(!rendered.contains(raw_char(0xFF)));
+        // Escapes that are cut off at the edge of the screen are shown partially.
+        let editor = Editor { screencols: 3, coloff: 2, ..editor };
+        assert!(editor.render_line(0, &line).contains("F>"));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::{
+    disk,
+    event_loop::Timer,
+    file_format::FileFormat,
+    history::{
+        Change,
+        EditKind,
+    },
+    input::{
+        Key,
+        KeyEvent,
+    },
+    prompt::{
+        Hooks,
+        PromptKind,
+    },
+    save,
+    Editor,
+};
+
+/// How often unsaved changes are written to the recovery file.
+const WRITE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Keeps a copy of the unsaved buffer next to the file, so that the changes
+/// are not lost if the editor crashes.
+pub(crate) struct Recovery {
+    last_write: Instant,
+    /// Cleared when the user keeps a recovery file left from an earlier
+    /// session, so that it is not overwritten.
+    enabled: bool,
+}
+
+impl Default for Recovery {
+    fn default() -> Recovery {
+        Recovery {
+            last_write: Instant::now(),
+            enabled: true,
+        }
+    }
+}
+
+/// Returns the path of the recovery file for `path`, e. g. `dir/.file.kiroswp`
+/// for `dir/file`.
+fn recovery_path(path: &Path) -> Option<PathBuf> {
+    let mut file_name = ".".to_owned();
+    file_name.push_str(path.file_name()?.to_str()?);
+    file_name.push_str(".kiroswp");
+    Some(path.with_file_name(file_name))
+}
+
+impl Editor {
+    fn recovery_path(&self) -> Option<PathBuf> {
+        self.filename.as_deref().and_then(recovery_path)
+    }
+
+    /// Tells whether unsaved changes are written to the recovery file. They
+    /// are not while the buffer refers to a lazily loaded file: copying all of
+    /// it every few seconds would stall the editor and take as much space as
+    /// the file itself.
+    fn backs_up_changes(&self) -> bool {
+        self.recovery.enabled && self.rows.mapping().is_none()
+    }
+
+    /// Makes sure that the recovery file is brought up to date with the
+    /// buffer, which must be done whenever the buffer changed.
+    pub(crate) fn schedule_recovery_file(&mut self) {
+        if self.backs_up_changes() && !self.timers.is_set(Timer::RecoveryFile) {
+            let deadline = self.recovery.last_write + WRITE_INTERVAL;
+            self.timers.set(Timer::RecoveryFile, deadline);
+        }
+    }
+
+    /// Writes the buffer to the recovery file when `Timer::RecoveryFile`
+    /// expired. Returns whether the screen needs to be redrawn.
+    pub(crate) fn update_recovery_file(&mut self) -> bool {
+        if !self.backs_up_changes() {
+            return false;
+        }
+        if !self.dirty {
+            self.remove_recovery_file();
+            return false;
+        }
+        let path = match self.recovery_path() {
+            Some(path) => path,
+            None => return false,
+        };
+        self.recovery.last_write = Instant::now();
+        match self.write_recovery_file(&path) {
+            Ok(()) => false,
+            Err(err) => {
+                // Retrying every time would just repeat the error.
+                self.recovery.enabled = false;
+                self.set_status(format!(
+                    "Could not write recovery file `{}`: {:?}",
+                    path.display(),
+                    err
+                ));
+                true
+            }
+        }
+    }
+
+    /// Writes unsaved changes to the recovery file right away, when the editor
+    /// is about to be ended.
+    pub(crate) fn save_for_recovery(&mut self) {
+        let path = match self.recovery_path() {
+            Some(path) if self.backs_up_changes() && self.dirty => path,
+            _ => return,
+        };
+        // There is no one left to report an error to.
+        self.write_recovery_file(&path).ok();
+    }
+
+    fn write_recovery_file(&self, path: &Path) -> io::Result<()> {
+        // The recovery file may contain sensitive data, so only the user can
+        // read it. Replacing it as a whole keeps the last copy if the editor
+        // crashes while writing it.
+        let (rows, file_format) = (&self.rows, self.file_format);
+        save::write_private_file(path, |file| file_format.write(rows, file)).map(drop)
+    }
+
+    /// Removes the recovery file, which must be done when the buffer was saved
+    /// or the editor exits normally.
+    pub(crate) fn remove_recovery_file(&mut self) {
+        if !self.recovery.enabled {
+            return;
+        }
+        if let Some(path) = self.recovery_path() {
+            // The file usually does not exist.
+            fs::remove_file(path).ok();
+        }
+        self.timers.cancel(Timer::RecoveryFile);
+    }
+
+    /// Asks what to do if a recovery file from an earlier session exists.
+    pub(crate) fn check_for_recovery_file(&mut self) {
+        match self.recovery_path() {
+            Some(path) if path.exists() => (),
+            _ => return,
+        }
+        let hooks = Hooks {
+            on_key: |editor, key| {
+                match key {
+                    KeyEvent { key: Key::Esc, .. } => return false,
+                    KeyEvent { key: Key::Char('r'), .. } => {
+                        editor.close_prompt();
+                        editor.restore_recovery_file();
+                    }
+                    KeyEvent { key: Key::Char('d'), .. } => {
+                        editor.close_prompt();
+                        editor.remove_recovery_file();
+                        editor.set_status("Recovery file discarded".into());
+                    }
+                    KeyEvent { key: Key::Char('v'), .. } => {
+                        let path = editor.filename.clone().unwrap();
+                        let recovery_path = editor.recovery_path().unwrap();
+                        if let Err(err) = disk::show_diff(&path, &recovery_path, "recovered") {
+                            editor.close_prompt();
+                            editor.set_status(format!("Could not show the diff: {:?}", err));
+                        }
+                    }
+                    _ => (),
+                }
+                true
+            },
+            on_cancel: |editor| {
+                editor.recovery.enabled = false;
+                editor.set_status(
+                    "Recovery file kept, changes in this session are not backed up".into(),
+                );
+            },
+            ..Hooks::default()
+        };
+        self.start_prompt(
+            PromptKind::Recovery,
+            "Found unsaved changes from an earlier session",
+            "(r = restore, d = discard, v = view changes, ESC to keep them)",
+            String::new(),
+            hooks,
+        );
+    }
+
+    /// Replaces the buffer with the contents of the recovery file. This is an
+    /// unsaved change that can be undone.
+    fn restore_recovery_file(&mut self) {
+        self.wait_for_loading();
+        let path = self.recovery_path().unwrap();
+        let recovered = File::open(&path).and_then(FileFormat::read);
+        // The recovery file is written in the buffer's format, which is not
+        // restored: that would not be undone together with the contents.
+        let (rows, _) = match recovered {
+            Ok(recovered) => recovered,
+            Err(err) => {
+                self.set_status(format!(
+                    "Could not read recovery file `{}`: {:?}",
+                    path.display(),
+                    err
+                ));
+                return;
+            }
+        };
+        let cursor_before = self.cursor();
+        if !self.rows.is_empty() {
+            let text = self.rows.iter().collect::<Vec<_>>().join("\n");
+            self.apply_change(Change::Delete { row: 0, col: 0, text });
+        }
+        self.set_cursor(0, 0);
+        self.insert_text(&rows.iter().collect::<Vec<_>>().join("\n"));
+        self.set_cursor(0, 0);
+        self.commit_edit(EditKind::Other, cursor_before);
+        self.set_status("Unsaved changes restored".into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lazy_load,
+        test_util::{
+            lock_mappings,
+            TempDir,
+        },
+    };
+
+    /// Opens a short file in `dir` and changes it. The file is loaded lazily if
+    /// it has at least `lazy_load_min_size` bytes.
+    fn edited_file(dir: &TempDir, lazy_load_min_size: u64) -> Editor {
+        let path = dir.path().join("file");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        let mut editor = Editor { lazy_load_min_size, ..Editor::default() };
+        editor.open(&path).unwrap();
+        editor.finish_loading();
+        assert!(editor.process_keypress(KeyEvent::plain(Key::Char('x'))));
+        assert!(editor.dirty);
+        editor
+    }
+
+    #[test]
+    fn changes_are_written_to_the_recovery_file() {
+        let dir = TempDir::new();
+        let mut editor = edited_file(&dir, lazy_load::MIN_SIZE);
+        assert!(editor.timers.is_set(Timer::RecoveryFile));
+        assert!(!editor.update_recovery_file());
+        let path = editor.recovery_path().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"xone\ntwo\n");
+        editor.remove_recovery_file();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn changes_to_mapped_files_are_not_written_to_the_recovery_file() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mut editor = edited_file(&dir, 1);
+        assert!(editor.rows.mapping().is_some());
+        assert!(!editor.timers.is_set(Timer::RecoveryFile));
+        assert!(!editor.update_recovery_file());
+        editor.save_for_recovery();
+        assert!(!editor.recovery_path().unwrap().exists());
+    }
+}
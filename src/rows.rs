@@ -0,0 +1,837 @@
+//! Storage for the lines of a buffer.
+//!
+//! Lines that were not edited since the file was read are ranges of the
+//! file's contents, which are kept in one string (or mapped into memory, see
+//! `lazy_load`), so opening a file does not allocate every line on its own.
+//! Lines are grouped into chunks of a few hundred, so that inserting or
+//! removing a line only moves the lines of one chunk. Such a line takes 8
+//! bytes: its offset from where its chunk starts in the file and its length.
+//! A line gets its own `String` when it is edited, which is kept in a list of
+//! its chunk.
+
+use std::{
+    convert::TryFrom,
+    io::{
+        self,
+        Write,
+    },
+    mem,
+    ops::{
+        Index,
+        Range,
+    },
+    str,
+    sync::Arc,
+};
+
+use crate::{
+    lazy_load::Mapping,
+    raw_bytes,
+};
+
+/// Number of lines in a chunk when it is created. Chunks are split when they
+/// grow to twice that size.
+const CHUNK_LEN: usize = 512;
+
+/// The length of a `Line` that refers to an `OwnedLine`.
+const OWNED: u32 = u32::MAX;
+
+/// A line of a mapped file, as found by `lazy_load`.
+pub(crate) enum MappedLine {
+    /// A line that is plain UTF-8, given by its byte range.
+    Plain(Range<usize>),
+    /// A line that is not plain UTF-8 (see `raw_bytes::is_plain`), so it
+    /// cannot be borrowed from the file.
+    Decoded { range: Range<usize>, text: String },
+}
+
+/// A line of a chunk: `len` bytes of `Rows::original` at offset `start` from
+/// the chunk's `base`, or entry `start` of the chunk's `owned` lines if `len`
+/// is `OWNED`.
+#[derive(Clone, Copy)]
+struct Line {
+    start: u32,
+    len: u32,
+}
+
+/// A line that has its own text: it was edited, or could not be borrowed from
+/// the file.
+#[derive(Default)]
+struct OwnedLine {
+    text: String,
+    /// Where the line ends in a mapped file if it was taken from it and not
+    /// edited since.
+    mapped_end: Option<usize>,
+}
+
+#[derive(Default)]
+struct Chunk {
+    /// The offset in `Rows::original` that the lines' offsets are relative to.
+    base: usize,
+    lines: Vec<Line>,
+    owned: Vec<OwnedLine>,
+    /// Entries of `owned` that are no longer used.
+    unused: Vec<u32>,
+}
+
+/// The contents of the file the rows were read from.
+enum Original {
+    /// The decoded contents of a file that was read completely.
+    Text(String),
+    /// The contents of a lazily loaded file. Lines referring to them are plain
+    /// UTF-8.
+    Mapped(Arc<Mapping>),
+    /// A copy of a mapped file that is about to be overwritten.
+    Copied(Vec<u8>),
+}
+
+impl Default for Original {
+    fn default() -> Original {
+        Original::Text(String::new())
+    }
+}
+
+/// The lines of a buffer, without line endings.
+#[derive(Default)]
+pub struct Rows {
+    original: Original,
+    chunks: Vec<Chunk>,
+    /// Index of the first line of every chunk.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Rows {
+    /// Splits `text` into lines at every `line_ending`, which has to end with
+    /// `\n`. A final line ending does not start another line.
+    pub fn from_text(text: String, line_ending: &str) -> Rows {
+        assert!(line_ending.ends_with('\n'));
+        let mut rows = Rows {
+            original: Original::Text(text),
+            ..Rows::default()
+        };
+        let text = match &rows.original {
+            Original::Text(text) => text,
+            _ => unreachable!(),
+        };
+        let mut start = 0;
+        // Searching for a `char` is a lot faster than for a `str`.
+        for (idx, _) in text.match_indices('\n') {
+            let end = idx + 1;
+            if text[..end].ends_with(line_ending) {
+                push_span(
+                    &mut rows.chunks,
+                    &rows.original,
+                    start..end - line_ending.len(),
+                );
+                start = end;
+            }
+        }
+        if start < text.len() {
+            push_span(&mut rows.chunks, &rows.original, start..text.len());
+        }
+        rows.update_starts(0);
+        rows.len = rows.chunks.iter().map(|chunk| chunk.lines.len()).sum();
+        rows
+    }
+
+    /// Creates empty rows for the lazily loaded file `mapping`, which are
+    /// filled by `append`.
+    pub(crate) fn from_mapping(mapping: Arc<Mapping>) -> Rows {
+        Rows {
+            original: Original::Mapped(mapping),
+            ..Rows::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&str> {
+        if idx >= self.len {
+            return None;
+        }
+        let (chunk, offset) = self.locate(idx);
+        let chunk = &self.chunks[chunk];
+        Some(chunk.get(&self.original, chunk.lines[offset]))
+    }
+
+    pub fn last(&self) -> Option<&str> {
+        self.get(self.len.checked_sub(1)?)
+    }
+
+    /// Iterates over all lines.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.lines(0..self.len)
+    }
+
+    /// Iterates over the lines in `range`.
+    pub fn lines(&self, range: Range<usize>) -> impl Iterator<Item = &str> {
+        assert!(range.start <= range.end && range.end <= self.len);
+        let (chunk, offset) = if range.start < self.len {
+            self.locate(range.start)
+        }
+        else {
+            (self.chunks.len(), 0)
+        };
+        let original = &self.original;
+        self.chunks[chunk..]
+            .iter()
+            .flat_map(move |chunk| {
+                chunk
+                    .lines
+                    .iter()
+                    .map(move |&line| chunk.get(original, line))
+            })
+            .skip(offset)
+            .take(range.end - range.start)
+    }
+
+    /// Returns line `idx` for editing.
+    pub fn line_mut(&mut self, idx: usize) -> &mut String {
+        assert!(idx < self.len, "line {} out of range", idx);
+        let (chunk, offset) = self.locate(idx);
+        let chunk = &mut self.chunks[chunk];
+        let line = chunk.lines[offset];
+        let entry = if line.is_owned() {
+            line.start
+        }
+        else {
+            let text = self.original.get(&line.range(chunk.base)).to_owned();
+            let line = chunk.own(OwnedLine { text, mapped_end: None });
+            chunk.lines[offset] = line;
+            line.start
+        };
+        let owned = &mut chunk.owned[entry as usize];
+        owned.mapped_end = None;
+        &mut owned.text
+    }
+
+    /// Inserts `line` before line `idx`.
+    pub fn insert(&mut self, idx: usize, line: String) {
+        self.insert_lines(idx, vec![line]);
+    }
+
+    /// Inserts `lines` before line `idx` and splits the chunk they end up in
+    /// if it gets too long.
+    pub fn insert_lines(&mut self, idx: usize, lines: Vec<String>) {
+        assert!(idx <= self.len, "line {} out of range", idx);
+        if lines.is_empty() {
+            return;
+        }
+        if self.chunks.is_empty() {
+            self.chunks.push(Chunk::default());
+        }
+        let (chunk, offset) = if idx < self.len {
+            self.locate(idx)
+        }
+        else {
+            let last = self.chunks.len() - 1;
+            (last, self.chunks[last].lines.len())
+        };
+        self.len += lines.len();
+        let target = &mut self.chunks[chunk];
+        let lines: Vec<_> = lines
+            .into_iter()
+            .map(|text| target.own(OwnedLine { text, mapped_end: None }))
+            .collect();
+        target.lines.splice(offset..offset, lines);
+        if target.lines.len() >= 2 * CHUNK_LEN {
+            let split = self.chunks.remove(chunk).split();
+            self.chunks.splice(chunk..chunk, split);
+        }
+        self.update_starts(chunk);
+    }
+
+    pub fn remove(&mut self, idx: usize) -> String {
+        assert!(idx < self.len, "line {} out of range", idx);
+        let (chunk, offset) = self.locate(idx);
+        let target = &mut self.chunks[chunk];
+        let line = target.lines.remove(offset);
+        let text = match target.disown(line) {
+            Some(text) => text,
+            None => self.original.get(&line.range(target.base)).to_owned(),
+        };
+        self.len -= 1;
+        self.update_starts(chunk);
+        text
+    }
+
+    /// Removes the lines in `range`.
+    pub fn remove_lines(&mut self, range: Range<usize>) {
+        assert!(range.start <= range.end && range.end <= self.len);
+        if range.start == range.end {
+            return;
+        }
+        let (first_chunk, first_offset) = self.locate(range.start);
+        let (last_chunk, last_offset) = self.locate(range.end - 1);
+        if first_chunk == last_chunk {
+            self.chunks[first_chunk].remove_lines(first_offset..last_offset + 1);
+        }
+        else {
+            self.chunks[last_chunk].remove_lines(0..last_offset + 1);
+            self.chunks.drain(first_chunk + 1..last_chunk);
+            let first = &mut self.chunks[first_chunk];
+            first.remove_lines(first_offset..first.lines.len());
+        }
+        self.len -= range.end - range.start;
+        self.update_starts(first_chunk);
+    }
+
+    /// Appends lines of a lazily loaded file.
+    pub(crate) fn append(&mut self, lines: Vec<MappedLine>) {
+        let first_changed = self.chunks.len().saturating_sub(1);
+        self.len += lines.len();
+        for line in lines {
+            match line {
+                MappedLine::Plain(range) => push_span(&mut self.chunks, &self.original, range),
+                MappedLine::Decoded { range, text } => {
+                    if self
+                        .chunks
+                        .last()
+                        .map_or(true, |chunk| chunk.lines.len() >= CHUNK_LEN)
+                    {
+                        self.chunks.push(Chunk::new(range.start));
+                    }
+                    let chunk = self.chunks.last_mut().unwrap();
+                    let line = chunk.own(OwnedLine { text, mapped_end: Some(range.end) });
+                    chunk.lines.push(line);
+                }
+            }
+        }
+        self.update_starts(first_changed);
+    }
+
+    /// Puts carriage returns back at the end of the lines of a lazily loaded
+    /// file, which were taken to be part of CRLF line endings before it
+    /// turned out that not all lines end like that.
+    pub(crate) fn restore_carriage_returns(&mut self) {
+        let bytes = match &self.original {
+            Original::Text(_) => return,
+            Original::Mapped(mapping) => mapping.as_bytes(),
+            Original::Copied(bytes) => bytes,
+        };
+        for chunk in &mut self.chunks {
+            for idx in 0..chunk.lines.len() {
+                let line = chunk.lines[idx];
+                if line.is_owned() {
+                    let owned = &mut chunk.owned[line.start as usize];
+                    if let Some(end) = owned
+                        .mapped_end
+                        .filter(|&end| bytes.get(end) == Some(&b'\r'))
+                    {
+                        owned.text.push('\r');
+                        owned.mapped_end = Some(end + 1);
+                    }
+                    continue;
+                }
+                let range = line.range(chunk.base);
+                if bytes.get(range.end) != Some(&b'\r') {
+                    continue;
+                }
+                let range = range.start..range.end + 1;
+                chunk.lines[idx] = match chunk.span(&range) {
+                    Some(line) => line,
+                    None => {
+                        let text = self.original.get(&range).to_owned();
+                        chunk.own(OwnedLine { text, mapped_end: Some(range.end) })
+                    }
+                };
+            }
+        }
+    }
+
+    /// Returns the mapped file that unchanged lines refer to.
+    pub(crate) fn mapping(&self) -> Option<&Mapping> {
+        match &self.original {
+            Original::Mapped(mapping) => Some(mapping),
+            _ => None,
+        }
+    }
+
+    /// Copies the contents of a mapped file into memory, which must be done
+    /// before the file is overwritten.
+    pub(crate) fn copy_mapping(&mut self) {
+        if let Original::Mapped(mapping) = &self.original {
+            self.original = Original::Copied(mapping.as_bytes().to_vec());
+        }
+    }
+
+    /// Writes the rows to `writer`, each followed by `line_ending` except for
+    /// the last one if `final_newline` is not set. Unchanged lines of a mapped
+    /// file are written straight from the mapping, together with the line
+    /// endings between them if these stay the same.
+    pub(crate) fn write(
+        &self,
+        writer: &mut dyn Write,
+        line_ending: &str,
+        final_newline: bool,
+    ) -> io::Result<()> {
+        let mapped = match &self.original {
+            Original::Text(_) => &[][..],
+            Original::Mapped(mapping) => mapping.as_bytes(),
+            Original::Copied(bytes) => bytes,
+        };
+        // The part of the mapping that is to be written next.
+        let mut span = 0..0;
+        let lines = self
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.lines.iter().map(move |&line| (chunk, line)));
+        for (idx, (chunk, line)) in lines.enumerate() {
+            let terminated = idx + 1 < self.len || final_newline;
+            if line.is_owned() || mapped.is_empty() {
+                writer.write_all(&mapped[span.clone()])?;
+                span = span.end..span.end;
+                writer.write_all(&raw_bytes::encode(chunk.get(&self.original, line)))?;
+                if terminated {
+                    writer.write_all(line_ending.as_bytes())?;
+                }
+                continue;
+            }
+            let range = line.range(chunk.base);
+            if span.end != range.start {
+                writer.write_all(&mapped[span])?;
+                span = range.clone();
+            }
+            span.end = range.end;
+            if terminated {
+                if mapped[span.end..].starts_with(line_ending.as_bytes()) {
+                    span.end += line_ending.len();
+                }
+                else {
+                    writer.write_all(&mapped[span.clone()])?;
+                    writer.write_all(line_ending.as_bytes())?;
+                    span = span.end..span.end;
+                }
+            }
+        }
+        writer.write_all(&mapped[span])
+    }
+
+    /// Returns the chunk containing line `idx` and the line's index in it.
+    fn locate(&self, idx: usize) -> (usize, usize) {
+        let chunk = match self.starts.binary_search(&idx) {
+            Ok(chunk) => chunk,
+            Err(next_chunk) => next_chunk - 1,
+        };
+        (chunk, idx - self.starts[chunk])
+    }
+
+    /// Drops empty chunks from `chunk` on and recomputes where the following
+    /// chunks start.
+    fn update_starts(&mut self, chunk: usize) {
+        let mut idx = chunk;
+        while idx < self.chunks.len() {
+            if self.chunks[idx].lines.is_empty() {
+                self.chunks.remove(idx);
+            }
+            else {
+                idx += 1;
+            }
+        }
+        self.starts.truncate(chunk.min(self.chunks.len()));
+        let mut start = match self.starts.last() {
+            Some(&last) => last + self.chunks[self.starts.len() - 1].lines.len(),
+            None => 0,
+        };
+        for chunk in &self.chunks[self.starts.len()..] {
+            self.starts.push(start);
+            start += chunk.lines.len();
+        }
+    }
+}
+
+/// Appends the line at `range` of `original` to the last of `chunks`, or to a
+/// new chunk if that one is full or starts too far before the line.
+fn push_span(chunks: &mut Vec<Chunk>, original: &Original, range: Range<usize>) {
+    let fits = chunks.last().map_or(false, |chunk| {
+        chunk.lines.len() < CHUNK_LEN && chunk.span(&range).is_some()
+    });
+    if !fits {
+        chunks.push(Chunk::new(range.start));
+    }
+    let chunk = chunks.last_mut().unwrap();
+    let line = match chunk.span(&range) {
+        Some(line) => line,
+        // The line is 4 GiB or longer.
+        None => {
+            let text = original.get(&range).to_owned();
+            chunk.own(OwnedLine { text, mapped_end: Some(range.end) })
+        }
+    };
+    chunk.lines.push(line);
+}
+
+impl Line {
+    fn is_owned(self) -> bool {
+        self.len == OWNED
+    }
+
+    /// Returns the byte range of a line that is not owned, given the `base`
+    /// of its chunk.
+    fn range(self, base: usize) -> Range<usize> {
+        let start = base + self.start as usize;
+        start..start + self.len as usize
+    }
+}
+
+impl Chunk {
+    fn new(base: usize) -> Chunk {
+        Chunk {
+            base,
+            lines: Vec::with_capacity(CHUNK_LEN),
+            ..Chunk::default()
+        }
+    }
+
+    fn get<'a>(&'a self, original: &'a Original, line: Line) -> &'a str {
+        if line.is_owned() {
+            &self.owned[line.start as usize].text
+        }
+        else {
+            original.get(&line.range(self.base))
+        }
+    }
+
+    /// Returns a line for `range` of the original, unless it is too far from
+    /// `base` or too long to be stored like that.
+    fn span(&self, range: &Range<usize>) -> Option<Line> {
+        let start = u32::try_from(range.start.checked_sub(self.base)?).ok()?;
+        let len = u32::try_from(range.len())
+            .ok()
+            .filter(|&len| len != OWNED)?;
+        Some(Line { start, len })
+    }
+
+    /// Adds `owned` to the chunk's owned lines and returns a line referring to
+    /// it.
+    fn own(&mut self, owned: OwnedLine) -> Line {
+        let entry = match self.unused.pop() {
+            Some(entry) => {
+                self.owned[entry as usize] = owned;
+                entry
+            }
+            None => {
+                self.owned.push(owned);
+                (self.owned.len() - 1) as u32
+            }
+        };
+        Line { start: entry, len: OWNED }
+    }
+
+    /// Frees the entry of a `line` that was removed, if it has one, and
+    /// returns its text.
+    fn disown(&mut self, line: Line) -> Option<String> {
+        if !line.is_owned() {
+            return None;
+        }
+        self.unused.push(line.start);
+        Some(mem::take(&mut self.owned[line.start as usize]).text)
+    }
+
+    fn remove_lines(&mut self, range: Range<usize>) {
+        let removed: Vec<_> = self.lines.drain(range).collect();
+        for line in removed {
+            self.disown(line);
+        }
+    }
+
+    /// Splits the chunk into chunks of `CHUNK_LEN` lines.
+    fn split(mut self) -> Vec<Chunk> {
+        let lines = mem::take(&mut self.lines);
+        lines
+            .chunks(CHUNK_LEN)
+            .map(|lines| {
+                let mut chunk = Chunk::new(self.base);
+                for &line in lines {
+                    let line = if line.is_owned() {
+                        chunk.own(mem::take(&mut self.owned[line.start as usize]))
+                    }
+                    else {
+                        line
+                    };
+                    chunk.lines.push(line);
+                }
+                chunk
+            })
+            .collect()
+    }
+}
+
+impl Original {
+    fn get(&self, range: &Range<usize>) -> &str {
+        match self {
+            Original::Text(text) => &text[range.clone()],
+            // Another program may change the file while it is mapped. Lines
+            // that are no longer valid UTF-8 then show up empty.
+            Original::Mapped(mapping) =>
+                str::from_utf8(&mapping.as_bytes()[range.clone()]).unwrap_or(""),
+            Original::Copied(bytes) => str::from_utf8(&bytes[range.clone()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Index<usize> for Rows {
+    type Output = str;
+
+    fn index(&self, idx: usize) -> &str {
+        self.get(idx)
+            .unwrap_or_else(|| panic!("line {} out of range", idx))
+    }
+}
+
+impl From<Vec<String>> for Rows {
+    fn from(lines: Vec<String>) -> Rows {
+        let mut rows = Rows::default();
+        rows.insert_lines(0, lines);
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{
+        self,
+        File,
+    };
+
+    use super::*;
+    use crate::test_util::{
+        lock_mappings,
+        Rng,
+        TempDir,
+    };
+
+    fn numbered_lines(range: Range<usize>) -> Vec<String> {
+        range.map(|n| format!("line {}", n)).collect()
+    }
+
+    /// Writes `bytes` to a file in `dir`, maps it and adds the lines ending in
+    /// `line_ending` like `lazy_load` does.
+    fn mapped_rows(dir: &TempDir, bytes: &[u8], line_ending: &str) -> Rows {
+        let path = dir.path().join("mapped");
+        fs::write(&path, bytes).unwrap();
+        let mapping = Mapping::new(&File::open(&path).unwrap(), bytes.len()).unwrap();
+        let mut rows = Rows::from_mapping(Arc::new(mapping));
+        let mut lines = vec![];
+        let mut start = 0;
+        while start < bytes.len() {
+            let end = (start..bytes.len())
+                .find(|&idx| bytes[idx..].starts_with(line_ending.as_bytes()))
+                .unwrap_or(bytes.len());
+            let line = &bytes[start..end];
+            lines.push(if raw_bytes::is_plain(line) {
+                MappedLine::Plain(start..end)
+            }
+            else {
+                MappedLine::Decoded {
+                    range: start..end,
+                    text: raw_bytes::decode(line.to_vec()),
+                }
+            });
+            start = end + line_ending.len();
+        }
+        rows.append(lines);
+        rows
+    }
+
+    fn written(rows: &Rows, line_ending: &str, final_newline: bool) -> Vec<u8> {
+        let mut bytes = vec![];
+        rows.write(&mut bytes, line_ending, final_newline).unwrap();
+        bytes
+    }
+
+    fn assert_same(rows: &Rows, expected: &[String]) {
+        assert_eq!(rows.len(), expected.len());
+        assert!(rows.iter().eq(expected.iter().map(String::as_str)));
+    }
+
+    /// Applies random edits to `rows` and to a vector with the same lines,
+    /// many of them across the boundaries of chunks.
+    fn check_random_edits(mut rows: Rows, seed: u64) {
+        let mut expected: Vec<String> = rows.iter().map(str::to_owned).collect();
+        let mut rng = Rng::new(seed);
+        let mut next_line = expected.len();
+        for _ in 0..1000 {
+            let len = expected.len();
+            // Lines are only added in bulk while there are few of them.
+            let count = if len < 3 * CHUNK_LEN {
+                rng.below(2 * CHUNK_LEN + 100)
+            }
+            else {
+                rng.below(10)
+            };
+            match rng.below(6) {
+                0 => {
+                    let idx = rng.below(len + 1);
+                    let line = format!("inserted {}", next_line);
+                    next_line += 1;
+                    rows.insert(idx, line.clone());
+                    expected.insert(idx, line);
+                }
+                1 => {
+                    let idx = rng.below(len + 1);
+                    let lines = numbered_lines(next_line..next_line + count);
+                    next_line += count;
+                    rows.insert_lines(idx, lines.clone());
+                    expected.splice(idx..idx, lines);
+                }
+                2 if len > 0 => {
+                    let idx = rng.below(len);
+                    assert_eq!(rows.remove(idx), expected.remove(idx));
+                }
+                3 => {
+                    let start = rng.below(len + 1);
+                    let end = (start + rng.below(2 * CHUNK_LEN + 100)).min(len);
+                    rows.remove_lines(start..end);
+                    expected.drain(start..end);
+                }
+                4 | 5 if len > 0 => {
+                    let idx = rng.below(len);
+                    let edit = *rng.pick(&["x", "", "ä\u{10FFFF}"]);
+                    rows.line_mut(idx).push_str(edit);
+                    expected[idx].push_str(edit);
+                }
+                _ => (),
+            }
+            if !expected.is_empty() {
+                let idx = rng.below(expected.len());
+                assert_eq!(&rows[idx], expected[idx]);
+            }
+            let start = rng.below(expected.len() + 1);
+            let end = start + rng.below(expected.len() - start + 1);
+            assert!(rows.lines(start..end).eq(expected[start..end].iter()));
+            assert_same(&rows, &expected);
+        }
+    }
+
+    #[test]
+    fn random_edits_of_read_lines() {
+        let text = numbered_lines(0..3 * CHUNK_LEN + 7).join("\n");
+        for seed in 1..4 {
+            check_random_edits(Rows::from_text(text.clone(), "\n"), seed);
+        }
+        check_random_edits(Rows::default(), 4);
+    }
+
+    #[test]
+    fn random_edits_of_mapped_lines() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let text = numbered_lines(0..3 * CHUNK_LEN + 7).join("\n");
+        for seed in 5..8 {
+            check_random_edits(mapped_rows(&dir, text.as_bytes(), "\n"), seed);
+        }
+    }
+
+    #[test]
+    fn insert_lines_splits_long_chunks() {
+        let mut rows = Rows::from(numbered_lines(0..10));
+        rows.insert_lines(5, numbered_lines(10..5 * CHUNK_LEN));
+        assert!(rows
+            .chunks
+            .iter()
+            .all(|chunk| chunk.lines.len() <= CHUNK_LEN));
+        assert_eq!(rows.len(), 5 * CHUNK_LEN);
+        assert_eq!(&rows[5], "line 10");
+        assert_eq!(&rows[5 * CHUNK_LEN - 1], "line 9");
+    }
+
+    #[test]
+    fn removed_lines_free_their_entries() {
+        let mut rows = Rows::from(numbered_lines(0..4));
+        rows.remove_lines(1..3);
+        rows.insert_lines(1, numbered_lines(4..6));
+        assert_eq!(rows.chunks[0].owned.len(), 4);
+        assert!(rows.iter().eq(vec!["line 0", "line 4", "line 5", "line 3"]));
+    }
+
+    #[test]
+    fn write_reproduces_read_text() {
+        for &(text, line_ending, final_newline) in &[
+            ("", "\n", true),
+            ("one", "\n", false),
+            ("one\ntwo\n", "\n", true),
+            ("one\n\ntwo", "\n", false),
+            ("one\r\ntwo\r\n", "\r\n", true),
+            ("one\r\ntwo\nthree\r\n", "\n", true),
+        ] {
+            let rows = Rows::from_text(text.into(), line_ending);
+            assert_eq!(written(&rows, line_ending, final_newline), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn write_reproduces_owned_lines() {
+        let mut rows = Rows::from_text("one\ntwo\nthree".into(), "\n");
+        rows.line_mut(1).push('\u{10FFFF}');
+        rows.insert(0, "zero".into());
+        assert_eq!(
+            written(&rows, "\r\n", true),
+            b"zero\r\none\r\ntwo\xFF\r\nthree\r\n"
+        );
+        let rows = Rows::from(vec!["a".into(), "\u{10FF80}".into()]);
+        assert_eq!(written(&rows, "\n", false), b"a\n\x80");
+    }
+
+    #[test]
+    fn write_reproduces_mapped_and_copied_lines() {
+        let _lock = lock_mappings();
+        let dir = TempDir::new();
+        let mut bytes = vec![];
+        for n in 0..2 * CHUNK_LEN {
+            if n % 100 == 0 {
+                bytes.extend_from_slice(b"invalid \xFF\xF4\x8F\xBE\x80");
+            }
+            bytes.extend_from_slice(format!("line {}\r\n", n).as_bytes());
+        }
+        for &line_ending in &["\r\n", "\n"] {
+            let rows = mapped_rows(&dir, &bytes, line_ending);
+            assert_eq!(written(&rows, line_ending, true), bytes);
+        }
+
+        let mut rows = mapped_rows(&dir, &bytes, "\r\n");
+        assert_eq!(
+            rows[0],
+            format!(
+                "invalid {}line 0",
+                raw_bytes::decode(b"\xFF\xF4\x8F\xBE\x80".to_vec())
+            )
+        );
+        // Lines that are edited, removed or written with another line ending
+        // are not copied from the mapping.
+        let mut expected: Vec<String> = rows.iter().map(str::to_owned).collect();
+        rows.line_mut(3).push('x');
+        expected[3].push('x');
+        rows.remove_lines(10..CHUNK_LEN + 10);
+        expected.drain(10..CHUNK_LEN + 10);
+        let encoded = |expected: &[String], line_ending: &str| {
+            expected
+                .iter()
+                .flat_map(|line| {
+                    raw_bytes::encode(line)
+                        .into_owned()
+                        .into_iter()
+                        .chain(line_ending.bytes())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(written(&rows, "\r\n", true), encoded(&expected, "\r\n"));
+        assert_eq!(written(&rows, "\n", true), encoded(&expected, "\n"));
+        let mut without_final_newline = encoded(&expected, "\r\n");
+        without_final_newline.truncate(without_final_newline.len() - 2);
+        assert_eq!(written(&rows, "\r\n", false), without_final_newline);
+
+        rows.copy_mapping();
+        assert!(rows.mapping().is_none());
+        fs::write(dir.path().join("mapped"), "").unwrap();
+        assert_eq!(written(&rows, "\r\n", true), encoded(&expected, "\r\n"));
+        assert_same(&rows, &expected);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    fs::{
+        self,
+        File,
+        Metadata,
+        OpenOptions,
+    },
+    io::{
+        self,
+        BufWriter,
+        Write,
+    },
+    os::unix::{
+        fs::{
+            MetadataExt,
+            OpenOptionsExt,
+        },
+        io::AsRawFd,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Appended to the file name of the temporary file a save is written to.
+const TEMP_FILE_SUFFIX: &str = "~kirosave";
+
+/// The most symlinks that are followed when resolving a path, like Linux’s
+/// `MAXSYMLINKS`.
+const MAX_SYMLINKS: usize = 40;
+
+/// Writes the contents produced by `write_contents` to `path` so that the file
+/// is either completely replaced or not touched at all, even if the system
+/// crashes. Symlinks are followed, and the file’s permissions and (as far as
+/// allowed) ownership are kept. Returns the number of bytes written.
+pub(crate) fn write_file(
+    path: &Path,
+    write_contents: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<u64> {
+    let path = resolve_for_writing(path)?;
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    if metadata
+        .as_ref()
+        .map_or(false, |metadata| metadata.nlink() > 1)
+    {
+        // Renaming over the file would detach it from its other hard links,
+        // so it has to be overwritten in place.
+        let file = OpenOptions::new().write(true).truncate(true).open(&path)?;
+        return write_and_sync(file, write_contents);
+    }
+
+    let create = |temp_file_path: &Path| {
+        let file = File::create(temp_file_path)?;
+        if let Some(metadata) = &metadata {
+            copy_ownership_and_permissions(&file, metadata)?;
+        }
+        Ok(file)
+    };
+    replace_file(&path, create, write_contents)
+}
+
+/// Replaces the file at `path` like `write_file`, but the new file can only be
+/// read by the user, whatever the old one's permissions were. Symlinks are not
+/// followed.
+pub(crate) fn write_private_file(
+    path: &Path,
+    write_contents: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<u64> {
+    let create = |temp_file_path: &Path| {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(temp_file_path)
+    };
+    replace_file(path, create, write_contents)
+}
+
+/// Writes the new contents of `path` to a temporary file created by `create`
+/// next to it, which is then renamed to `path`.
+fn replace_file(
+    path: &Path,
+    create: impl FnOnce(&Path) -> io::Result<File>,
+    write_contents: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<u64> {
+    let temp_file_path = {
+        let mut file_name = path.file_name().unwrap().to_os_string();
+        file_name.push(TEMP_FILE_SUFFIX);
+        path.with_file_name(file_name)
+    };
+    let result = (|| -> io::Result<u64> {
+        let file = create(&temp_file_path)?;
+        let bytes_written = write_and_sync(file, write_contents)?;
+        fs::rename(&temp_file_path, path)?;
+        Ok(bytes_written)
+    })();
+    if result.is_err() {
+        // The original error is more interesting than a failure to clean up.
+        fs::remove_file(&temp_file_path).ok();
+    }
+    let bytes_written = result?;
+
+    // The rename is only durable once the directory entry is on disk.
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(bytes_written)
+}
+
+/// Tells whether `write_file` would overwrite the file at `path` in place
+/// instead of replacing it.
+pub(crate) fn overwrites_in_place(path: &Path) -> io::Result<bool> {
+    match fs::metadata(resolve_for_writing(path)?) {
+        Ok(metadata) => Ok(metadata.nlink() > 1),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn write_and_sync(
+    file: File,
+    write_contents: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<u64> {
+    let mut writer = BufWriter::new(file);
+    write_contents(&mut writer)?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    Ok(file.metadata()?.len())
+}
+
+fn copy_ownership_and_permissions(file: &File, metadata: &Metadata) -> io::Result<()> {
+    // Only root may give files away, so for everyone else this fails unless
+    // the owner already is the same. The file is still saved in that case.
+    unsafe {
+        libc::fchown(file.as_raw_fd(), metadata.uid(), metadata.gid());
+    }
+    // Changing the owner may clear the setuid and setgid bits, so the
+    // permissions are set afterwards.
+    file.set_permissions(metadata.permissions())
+}
+
+/// Returns the absolute path of the file that writing to `path` should
+/// replace: all symlinks are resolved, including a dangling one at the end,
+/// and the file itself does not need to exist as long as its parent directory
+/// does.
+fn resolve_for_writing(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_owned();
+    // One more round than there are symlinks to follow, to find out that the
+    // last one points to no symlink.
+    for _ in 0..=MAX_SYMLINKS {
+        match fs::canonicalize(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            result => return result,
+        }
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.to_owned(),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => fs::canonicalize(parent)?,
+            _ => fs::canonicalize(".")?,
+        };
+        let path_in_parent = parent.join(file_name);
+        match fs::read_link(&path_in_parent) {
+            // A dangling symlink: the file is created where it points to.
+            Ok(target) => path = parent.join(target),
+            Err(_) => return Ok(path_in_parent),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Too many levels of symbolic links",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{
+        symlink,
+        PermissionsExt,
+    };
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn write(path: &Path, contents: &str) -> io::Result<u64> {
+        write_file(path, |writer| writer.write_all(contents.as_bytes()))
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// Lists the file names in `dir`, to check that no temporary file is left.
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Creates a chain of `count` symlinks in `dir` that ends at `target` and
+    /// returns the first one.
+    fn symlink_chain(dir: &Path, target: &str, count: usize) -> PathBuf {
+        let mut target = PathBuf::from(target);
+        for idx in 0..count {
+            let link = dir.join(format!("link{}", idx));
+            symlink(&target, &link).unwrap();
+            target = link;
+        }
+        target
+    }
+
+    #[test]
+    fn permissions_are_kept() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+        assert_eq!(write(&path, "new").unwrap(), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(mode(&path), 0o751);
+        assert_eq!(file_names(dir.path()), ["file"]);
+
+        let path = dir.path().join("new file");
+        write(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    }
+
+    #[test]
+    fn private_files_are_only_readable_by_the_user() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_private_file(&path, |writer| writer.write_all(b"new")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn symlinks_are_written_through() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("file"), "old").unwrap();
+        let link = symlink_chain(dir.path(), "file", 2);
+        write(&link, "new").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(dir.path().join("file")).unwrap(), "new");
+
+        // The file a dangling symlink points to is created.
+        let link = dir.path().join("dangling");
+        symlink("missing", &link).unwrap();
+        write(&link, "new").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("missing")).unwrap(),
+            "new"
+        );
+    }
+
+    #[test]
+    fn at_most_40_symlinks_are_followed() {
+        for &target in &["file", "missing"] {
+            let dir = TempDir::new();
+            fs::write(dir.path().join("file"), "old").unwrap();
+            let link = symlink_chain(dir.path(), target, MAX_SYMLINKS);
+            write(&link, "new").unwrap();
+            assert_eq!(fs::read_to_string(dir.path().join(target)).unwrap(), "new");
+
+            let too_long = dir.path().join("too long");
+            symlink(&link, &too_long).unwrap();
+            assert!(write(&too_long, "newer").is_err());
+            assert!(overwrites_in_place(&too_long).is_err());
+            assert_eq!(fs::read_to_string(dir.path().join(target)).unwrap(), "new");
+        }
+
+        let dir = TempDir::new();
+        let link = dir.path().join("loop");
+        symlink("loop", &link).unwrap();
+        assert!(write(&link, "new").is_err());
+    }
+
+    #[test]
+    fn hard_links_are_overwritten_in_place() {
+        let dir = TempDir::new();
+        let (path, other_path) = (dir.path().join("file"), dir.path().join("other"));
+        assert!(!overwrites_in_place(&path).unwrap());
+        fs::write(&path, "old").unwrap();
+        assert!(!overwrites_in_place(&path).unwrap());
+
+        fs::hard_link(&path, &other_path).unwrap();
+        assert!(overwrites_in_place(&path).unwrap());
+        let inode = fs::metadata(&path).unwrap().ino();
+        write(&path, "new").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(fs::read_to_string(&other_path).unwrap(), "new");
+        assert_eq!(file_names(dir.path()), ["file", "other"]);
+    }
+
+    #[test]
+    fn failed_write_keeps_the_original() {
+        let dir = TempDir::new();
+        let path = dir.path().join("file");
+        fs::write(&path, "old").unwrap();
+        let result = write_file(&path, |writer| {
+            writer.write_all(b"partly new")?;
+            Err(io::Error::new(io::ErrorKind::Other, "failed"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "failed");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(file_names(dir.path()), ["file"]);
+
+        // The temporary file is not created in a missing directory.
+        assert!(write(&dir.path().join("missing/file"), "new").is_err());
+        assert_eq!(file_names(dir.path()), ["file"]);
+    }
+}
@@ -0,0 +1,272 @@
+use std::ops::Range;
+
+use crate::{
+    input::Key,
+    prompt::{
+        Hooks,
+        PromptKind,
+    },
+    unicode,
+    Cursor,
+    Editor,
+    Rows,
+};
+
+pub(crate) struct Search {
+    query: String,
+    saved_cursor: Cursor,
+    current_match: Option<(usize, usize)>,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+impl Search {
+    fn new(saved_cursor: Cursor) -> Search {
+        Search {
+            query: String::new(),
+            saved_cursor,
+            current_match: None,
+        }
+    }
+}
+
+impl Editor {
+    pub(crate) fn start_search(&mut self) {
+        self.wait_for_loading();
+        self.search = Some(Search::new(self.cursor()));
+        let hooks = Hooks {
+            on_key: |editor, key| match key.key {
+                Key::Down | Key::Right => {
+                    editor.search_next(Direction::Forward);
+                    true
+                }
+                Key::Up | Key::Left => {
+                    editor.search_next(Direction::Backward);
+                    true
+                }
+                _ => false,
+            },
+            on_change: |editor, query| {
+                editor.search.as_mut().unwrap().query = query.to_owned();
+                editor.search_from_saved_cursor();
+            },
+            on_confirm: |editor, _| editor.search = None,
+            on_cancel: |editor| {
+                let saved_cursor = editor.search.as_ref().unwrap().saved_cursor;
+                editor.search = None;
+                editor.restore_cursor(saved_cursor);
+            },
+            ..Hooks::default()
+        };
+        self.start_prompt(
+            PromptKind::Search,
+            "Search",
+            "(Use Esc/Arrows/Return)",
+            String::new(),
+            hooks,
+        );
+    }
+
+    /// Returns the byte range of the current search match in row `filerow`,
+    /// if there is one. It is widened to whole grapheme clusters.
+    pub(crate) fn search_match(&self, filerow: usize) -> Option<Range<usize>> {
+        let search = self.search.as_ref()?;
+        let (row, col) = search.current_match?;
+        if row != filerow {
+            return None;
+        }
+        let line = &self.rows[row];
+        let end = col + search.query.len();
+        let end = match unicode::boundary_at_or_before(line, end) {
+            boundary if boundary < end => unicode::next_boundary(line, boundary),
+            _ => end,
+        };
+        Some(unicode::boundary_at_or_before(line, col)..end)
+    }
+
+    fn search_from_saved_cursor(&mut self) {
+        let search = self.search.as_mut().unwrap();
+        let saved_cursor = search.saved_cursor;
+        search.current_match = None;
+        self.restore_cursor(saved_cursor);
+        let position = (self.cy, self.cx);
+        self.jump_to_match(position, true, Direction::Forward);
+    }
+
+    fn search_next(&mut self, direction: Direction) {
+        let search = self.search.as_ref().unwrap();
+        let position = search.current_match.unwrap_or((self.cy, self.cx));
+        self.jump_to_match(position, false, direction);
+    }
+
+    fn jump_to_match(&mut self, (row, col): (usize, usize), inclusive: bool, direction: Direction) {
+        let search = self.search.as_ref().unwrap();
+        let found = find(&self.rows, &search.query, (row, col), inclusive, direction);
+        if let Some((row, col)) = found {
+            // A match may start inside a grapheme cluster, e. g. with a
+            // combining character. It is remembered as it is, so that the
+            // next search starts after it.
+            let col = unicode::boundary_at_or_before(&self.rows[row], col);
+            self.set_cursor(row, col);
+        }
+        self.search.as_mut().unwrap().current_match = found;
+    }
+}
+
+/// Finds the next occurrence of `query` in `rows`, starting at `row`/`col` and
+/// wrapping around at the start/end of the buffer. A match at exactly
+/// `row`/`col` is only considered if `inclusive` is set.
+fn find(
+    rows: &Rows,
+    query: &str,
+    (row, col): (usize, usize),
+    inclusive: bool,
+    direction: Direction,
+) -> Option<(usize, usize)> {
+    if query.is_empty() || rows.is_empty() {
+        return None;
+    }
+    let row = row.min(rows.len() - 1);
+    let len = rows.len();
+    (0..=len).find_map(|i| {
+        let (filerow, found) = match direction {
+            Direction::Forward => {
+                let filerow = (row + i) % len;
+                let mut matches = rows[filerow].match_indices(query).map(|(idx, _)| idx);
+                let found = match i {
+                    0 => matches.find(|&idx| idx > col || (inclusive && idx == col)),
+                    _ => matches.next(),
+                };
+                (filerow, found)
+            }
+            Direction::Backward => {
+                let filerow = (row + len - i % len) % len;
+                let mut matches = rows[filerow].rmatch_indices(query).map(|(idx, _)| idx);
+                let found = match i {
+                    0 => matches.find(|&idx| idx < col || (inclusive && idx == col)),
+                    _ => matches.next(),
+                };
+                (filerow, found)
+            }
+        };
+        found.map(|col| (filerow, col))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::KeyEvent,
+        test_util::use_utf8_locale,
+    };
+
+    fn editor_with(lines: &[&str]) -> Editor {
+        let mut editor = Editor::default();
+        for (idx, line) in lines.iter().enumerate() {
+            editor.rows.insert(idx, (*line).to_owned());
+        }
+        editor
+    }
+
+    fn press(editor: &mut Editor, keys: &[Key]) {
+        for &key in keys {
+            assert!(editor.process_keypress(KeyEvent::plain(key)));
+        }
+    }
+
+    fn search(editor: &mut Editor, query: &str) {
+        assert!(editor.process_keypress(KeyEvent::ctrl('f')));
+        press(editor, &query.chars().map(Key::Char).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn matches_wrap_around_forward() {
+        let mut editor = editor_with(&["ab ab", "b", "xab"]);
+        editor.set_cursor(1, 0);
+        search(&mut editor, "ab");
+        assert_eq!((editor.cy, editor.cx), (2, 1));
+        for &(key, position) in &[
+            (Key::Down, (0, 0)),
+            (Key::Right, (0, 3)),
+            (Key::Down, (2, 1)),
+            (Key::Right, (0, 0)),
+        ] {
+            press(&mut editor, &[key]);
+            assert_eq!((editor.cy, editor.cx), position);
+        }
+        press(&mut editor, &[Key::Enter]);
+        assert!(editor.search.is_none());
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+    }
+
+    #[test]
+    fn matches_wrap_around_backward() {
+        let mut editor = editor_with(&["ab ab", "b", "xab"]);
+        editor.set_cursor(0, 2);
+        search(&mut editor, "ab");
+        assert_eq!((editor.cy, editor.cx), (0, 3));
+        for &(key, position) in &[
+            (Key::Up, (0, 0)),
+            (Key::Left, (2, 1)),
+            (Key::Up, (0, 3)),
+            (Key::Left, (0, 0)),
+        ] {
+            press(&mut editor, &[key]);
+            assert_eq!((editor.cy, editor.cx), position);
+        }
+    }
+
+    #[test]
+    fn single_match_is_found_again() {
+        let mut editor = editor_with(&["one", "two"]);
+        search(&mut editor, "tw");
+        press(&mut editor, &[Key::Down, Key::Down, Key::Up]);
+        assert_eq!((editor.cy, editor.cx), (1, 0));
+        assert_eq!(editor.search_match(1), Some(0..2));
+        assert_eq!(editor.search_match(0), None);
+    }
+
+    #[test]
+    fn cancel_restores_the_cursor() {
+        let mut editor = editor_with(&["one", "two", "one"]);
+        editor.set_cursor(1, 2);
+        search(&mut editor, "one");
+        press(&mut editor, &[Key::Down]);
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+        press(&mut editor, &[Key::Esc]);
+        assert!(editor.search.is_none());
+        assert!(!editor.is_prompting());
+        assert_eq!((editor.cy, editor.cx), (1, 2));
+
+        // Changing the query searches from the original position again.
+        search(&mut editor, "onx");
+        assert_eq!(editor.search.as_ref().unwrap().current_match, None);
+        assert_eq!((editor.cy, editor.cx), (1, 2));
+    }
+
+    #[test]
+    fn matches_are_snapped_to_grapheme_clusters() {
+        use_utf8_locale();
+        // The query is the combining accent of both "é"s.
+        let mut editor = editor_with(&["e\u{301}te\u{301}"]);
+        search(&mut editor, "\u{301}");
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+        assert_eq!(editor.search_match(0), Some(0..3));
+        press(&mut editor, &[Key::Down]);
+        assert_eq!((editor.cy, editor.cx), (0, 4));
+        assert_eq!(editor.search_match(0), Some(4..7));
+        press(&mut editor, &[Key::Down]);
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+
+        // A match that ends inside a cluster covers all of it.
+        press(&mut editor, &[Key::Esc]);
+        search(&mut editor, "te");
+        assert_eq!((editor.cy, editor.cx), (0, 3));
+        assert_eq!(editor.search_match(0), Some(3..7));
+    }
+}
@@ -0,0 +1,513 @@
+use std::path::Path;
+
+use crate::{
+    ansi::Color,
+    history::Change,
+    Rows,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Highlight {
+    Normal,
+    Comment,
+    Keyword,
+    Type,
+    String,
+    Number,
+}
+
+impl Highlight {
+    pub(crate) fn color(self) -> Color {
+        match self {
+            Highlight::Normal => Color::Default,
+            Highlight::Comment => Color::Cyan,
+            Highlight::Keyword => Color::Yellow,
+            Highlight::Type => Color::Green,
+            Highlight::String => Color::Magenta,
+            Highlight::Number => Color::Red,
+        }
+    }
+}
+
+/// Describes how to highlight one file type.
+pub(crate) struct Syntax {
+    pub(crate) name: &'static str,
+    extensions: &'static [&'static str],
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    singleline_comment: Option<&'static str>,
+    multiline_comment: Option<(&'static str, &'static str)>,
+    string_quotes: &'static [u8],
+    /// Whether `'` starts a character literal like `'a'` or `'\n'`. Other
+    /// uses of `'` (e. g. Rust lifetimes) are not highlighted.
+    char_literals: bool,
+}
+
+static SYNTAXES: &[Syntax] = &[
+    Syntax {
+        name: "c",
+        extensions: &["c", "h", "cpp", "hpp", "cc"],
+        keywords: &[
+            "auto", "break", "case", "const", "continue", "default", "do", "else", "enum",
+            "extern", "for", "goto", "if", "inline", "register", "restrict", "return", "sizeof",
+            "static", "struct", "switch", "typedef", "union", "volatile", "while", "NULL", "true",
+            "false",
+        ],
+        types: &[
+            "int", "long", "double", "float", "char", "unsigned", "signed", "void", "short",
+            "bool", "size_t", "ssize_t", "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t",
+            "uint16_t", "uint32_t", "uint64_t",
+        ],
+        singleline_comment: Some("//"),
+        multiline_comment: Some(("/*", "*/")),
+        string_quotes: b"\"",
+        char_literals: true,
+    },
+    Syntax {
+        name: "rust",
+        extensions: &["rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        types: &[
+            "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+            "f32", "f64", "bool", "char", "str", "String", "Vec", "Box", "Option", "Result",
+            "Some", "None", "Ok", "Err",
+        ],
+        singleline_comment: Some("//"),
+        multiline_comment: Some(("/*", "*/")),
+        string_quotes: b"\"",
+        char_literals: true,
+    },
+    Syntax {
+        name: "toml",
+        extensions: &["toml"],
+        keywords: &["true", "false"],
+        types: &[],
+        singleline_comment: Some("#"),
+        multiline_comment: None,
+        string_quotes: b"\"'",
+        char_literals: false,
+    },
+];
+
+/// Selects the syntax definition for `path` by its extension.
+pub(crate) fn for_path(path: &Path) -> Option<&'static Syntax> {
+    let extension = path.extension()?.to_str()?;
+    SYNTAXES
+        .iter()
+        .find(|syntax| syntax.extensions.contains(&extension))
+}
+
+impl Syntax {
+    /// Classifies every byte of `line`. `in_comment` tells whether the line
+    /// starts inside a multi-line comment. Returns the classes and whether a
+    /// multi-line comment is still open at the end of the line.
+    pub(crate) fn highlight(&self, line: &str, mut in_comment: bool) -> (Vec<Highlight>, bool) {
+        let bytes = line.as_bytes();
+        let mut hl = vec![Highlight::Normal; bytes.len()];
+        let mut in_string = None;
+        let mut previous_separator = true;
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            let previous_hl = if i > 0 { hl[i - 1] } else { Highlight::Normal };
+
+            if in_comment {
+                let (_, end) = self.multiline_comment.unwrap();
+                if rest.starts_with(end.as_bytes()) {
+                    fill(&mut hl[i..i + end.len()], Highlight::Comment);
+                    i += end.len();
+                    in_comment = false;
+                    previous_separator = true;
+                }
+                else {
+                    hl[i] = Highlight::Comment;
+                    i += 1;
+                }
+                continue;
+            }
+
+            if let Some(quote) = in_string {
+                hl[i] = Highlight::String;
+                if bytes[i] == b'\\' && i + 1 < bytes.len() {
+                    hl[i + 1] = Highlight::String;
+                    i += 2;
+                    continue;
+                }
+                if bytes[i] == quote {
+                    in_string = None;
+                }
+                i += 1;
+                previous_separator = true;
+                continue;
+            }
+
+            if let Some(start) = self.singleline_comment {
+                if rest.starts_with(start.as_bytes()) {
+                    fill(&mut hl[i..], Highlight::Comment);
+                    break;
+                }
+            }
+
+            if let Some((start, _)) = self.multiline_comment {
+                if rest.starts_with(start.as_bytes()) {
+                    fill(&mut hl[i..i + start.len()], Highlight::Comment);
+                    i += start.len();
+                    in_comment = true;
+                    continue;
+                }
+            }
+
+            if self.string_quotes.contains(&bytes[i]) {
+                in_string = Some(bytes[i]);
+                hl[i] = Highlight::String;
+                i += 1;
+                continue;
+            }
+
+            if self.char_literals && bytes[i] == b'\'' {
+                if let Some(len) = char_literal_len(&line[i..]) {
+                    fill(&mut hl[i..i + len], Highlight::String);
+                    i += len;
+                    previous_separator = true;
+                    continue;
+                }
+            }
+
+            let continues_number = previous_hl == Highlight::Number
+                && (bytes[i].is_ascii_alphanumeric()
+                    || bytes[i] == b'_'
+                    || (bytes[i] == b'.' && rest.get(1).map_or(false, u8::is_ascii_digit)));
+            if (bytes[i].is_ascii_digit() && previous_separator) || continues_number {
+                hl[i] = Highlight::Number;
+                i += 1;
+                previous_separator = false;
+                continue;
+            }
+
+            if previous_separator {
+                let keyword = self
+                    .keywords
+                    .iter()
+                    .map(|keyword| (keyword, Highlight::Keyword))
+                    .chain(self.types.iter().map(|ty| (ty, Highlight::Type)))
+                    .find(|(word, _)| {
+                        rest.starts_with(word.as_bytes())
+                            && rest.get(word.len()).map_or(true, |&c| is_separator(c))
+                    });
+                if let Some((word, class)) = keyword {
+                    fill(&mut hl[i..i + word.len()], class);
+                    i += word.len();
+                    previous_separator = false;
+                    continue;
+                }
+            }
+
+            previous_separator = is_separator(bytes[i]);
+            i += 1;
+        }
+
+        (hl, in_comment)
+    }
+}
+
+/// Highlighting state of a buffer: the syntax used and, for every row,
+/// whether it starts inside a multi-line comment.
+pub(crate) struct Highlighter {
+    syntax: Option<&'static Syntax>,
+    in_comment: Vec<bool>,
+    /// Number of rows at the start whose state is known. Rows are only
+    /// highlighted when they are shown (see `highlight_up_to`), so that
+    /// opening a huge file does not highlight all of it.
+    known: usize,
+}
+
+impl Highlighter {
+    pub(crate) fn new(syntax: Option<&'static Syntax>, rows: &Rows) -> Highlighter {
+        Highlighter {
+            syntax,
+            in_comment: vec![false; rows.len()],
+            // The first row never starts inside a comment.
+            known: rows.len().min(1),
+        }
+    }
+
+    pub(crate) fn syntax(&self) -> Option<&'static Syntax> {
+        self.syntax
+    }
+
+    /// Determines the state of the rows before `end`, which have to be
+    /// highlighted before they are shown.
+    pub(crate) fn highlight_up_to(&mut self, rows: &Rows, end: usize) {
+        let syntax = match self.syntax {
+            Some(syntax) => syntax,
+            None => return,
+        };
+        let end = end.min(rows.len());
+        if self.known >= end {
+            return;
+        }
+        let first_row = self.known - 1;
+        for (filerow, row) in (first_row..).zip(rows.lines(first_row..end - 1)) {
+            let (_, in_comment) = syntax.highlight(row, self.in_comment[filerow]);
+            self.in_comment[filerow + 1] = in_comment;
+        }
+        self.known = end;
+    }
+
+    /// Returns the highlight class of every byte in row `filerow`, or `None`
+    /// if no syntax is selected. The row must have been passed to
+    /// `highlight_up_to`.
+    pub(crate) fn highlight_row(&self, rows: &Rows, filerow: usize) -> Option<Vec<Highlight>> {
+        let syntax = self.syntax?;
+        let (hl, _) = syntax.highlight(&rows[filerow], self.in_comment[filerow]);
+        Some(hl)
+    }
+
+    /// Updates the highlighting state after `change` was applied to `rows`.
+    pub(crate) fn update(&mut self, rows: &Rows, change: &Change) {
+        let (first_row, last_row) = match change {
+            Change::Insert { row, text, .. } => {
+                let inserted = text.matches('\n').count();
+                self.in_comment
+                    .splice(row + 1..row + 1, (0..inserted).map(|_| false));
+                if *row < self.known {
+                    self.known += inserted;
+                }
+                (*row, row + inserted)
+            }
+            Change::Delete { row, text, .. } => {
+                let removed = text.matches('\n').count();
+                self.in_comment.drain(row + 1..row + 1 + removed);
+                if *row < self.known {
+                    self.known = self.known.saturating_sub(removed).max(row + 1);
+                }
+                (*row, *row)
+            }
+            Change::InsertLine { row, .. } => {
+                self.in_comment.insert(*row, false);
+                if *row < self.known {
+                    self.known += 1;
+                }
+                (row.saturating_sub(1), *row)
+            }
+            Change::RemoveLine { row, .. } => {
+                self.in_comment.remove(*row);
+                if *row < self.known {
+                    self.known -= 1;
+                }
+                (row.saturating_sub(1), *row)
+            }
+        };
+        self.known = self.known.max(rows.len().min(1));
+        self.update_from(rows, first_row, last_row);
+    }
+
+    /// Makes room for the state of rows that were appended to `rows`. They
+    /// are highlighted when they are shown.
+    pub(crate) fn rows_appended(&mut self, rows: &Rows) {
+        self.in_comment.resize(rows.len(), false);
+        self.known = self.known.max(rows.len().min(1));
+    }
+
+    /// Recomputes the state of the rows following `first_row` up to `last_row`.
+    /// If the state of the row after that changes as well, it and the rows
+    /// following it are highlighted again when they are shown.
+    fn update_from(&mut self, rows: &Rows, first_row: usize, last_row: usize) {
+        let syntax = match self.syntax {
+            Some(syntax) => syntax,
+            None => return,
+        };
+        if let Some(in_comment) = self.in_comment.first_mut() {
+            *in_comment = false;
+        }
+        for filerow in first_row..self.known.saturating_sub(1) {
+            let (_, in_comment) = syntax.highlight(&rows[filerow], self.in_comment[filerow]);
+            if filerow >= last_row {
+                if self.in_comment[filerow + 1] != in_comment {
+                    self.known = filerow + 1;
+                }
+                break;
+            }
+            self.in_comment[filerow + 1] = in_comment;
+        }
+    }
+}
+
+fn fill(hl: &mut [Highlight], class: Highlight) {
+    hl.iter_mut().for_each(|hl| *hl = class);
+}
+
+fn is_separator(c: u8) -> bool {
+    !(c.is_ascii_alphanumeric() || c == b'_' || !c.is_ascii())
+}
+
+/// Returns the length of the character literal at the start of `s` (which
+/// starts with `'`), or `None` if there is none.
+fn char_literal_len(s: &str) -> Option<usize> {
+    let mut chars = s.char_indices().skip(1);
+    let (_, c) = chars.next()?;
+    if c == '\\' {
+        chars.next()?;
+        chars
+            .take(10)
+            .find(|&(_, c)| c == '\'')
+            .map(|(idx, _)| idx + 1)
+    }
+    else {
+        chars
+            .next()
+            .filter(|&(_, c)| c == '\'')
+            .map(|(idx, _)| idx + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::{
+            Key,
+            KeyEvent,
+            Modifiers,
+        },
+        test_util::Rng,
+        Editor,
+        SystemClipboard,
+    };
+
+    fn c_editor() -> Editor {
+        let syntax = for_path(Path::new("test.c"));
+        let mut editor = Editor {
+            highlighter: Highlighter::new(syntax, &Rows::default()),
+            ..Editor::default()
+        };
+        editor.set_system_clipboard(SystemClipboard::Disabled);
+        editor
+    }
+
+    fn type_text(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            let key = if c == '\n' { Key::Enter } else { Key::Char(c) };
+            assert!(editor.process_keypress(KeyEvent::plain(key)));
+        }
+    }
+
+    /// Determines whether each row starts inside a comment from scratch.
+    fn expected_in_comment(editor: &Editor) -> Vec<bool> {
+        let syntax = editor.highlighter.syntax.unwrap();
+        let mut in_comment = false;
+        editor
+            .rows
+            .iter()
+            .map(|row| {
+                let starts_in_comment = in_comment;
+                in_comment = syntax.highlight(row, in_comment).1;
+                starts_in_comment
+            })
+            .collect()
+    }
+
+    /// Highlights the rows before `end`, like showing them does, and returns
+    /// their state.
+    fn in_comment(editor: &mut Editor, end: usize) -> Vec<bool> {
+        editor.highlighter.highlight_up_to(&editor.rows, end);
+        editor.highlighter.in_comment[..end].to_vec()
+    }
+
+    #[test]
+    fn comments_are_opened_and_closed_by_edits() {
+        let mut editor = c_editor();
+        type_text(&mut editor, "a\nb\nc\nd\ne");
+        assert_eq!(in_comment(&mut editor, 5), [false; 5]);
+
+        // Only the first rows are shown, the others are updated later.
+        editor.set_cursor(0, 1);
+        type_text(&mut editor, "/*");
+        assert_eq!(in_comment(&mut editor, 2), [false, true]);
+        assert_eq!(in_comment(&mut editor, 5), [false, true, true, true, true]);
+
+        editor.set_cursor(2, 1);
+        type_text(&mut editor, "*/");
+        assert_eq!(
+            in_comment(&mut editor, 5),
+            [false, true, true, false, false]
+        );
+
+        // Joining the rows with the comment start and end.
+        let backspace = KeyEvent::plain(Key::Backspace);
+        for _ in 0..2 {
+            editor.set_cursor(1, 0);
+            assert!(editor.process_keypress(backspace));
+        }
+        assert_eq!(&editor.rows[0], "a/*bc*/");
+        assert_eq!(in_comment(&mut editor, 3), [false, false, false]);
+
+        // Splitting them again, and undoing that.
+        editor.set_cursor(0, 4);
+        type_text(&mut editor, "\n");
+        assert_eq!(in_comment(&mut editor, 4), [false, true, false, false]);
+        assert!(editor.process_keypress(KeyEvent::ctrl('z')));
+        assert_eq!(in_comment(&mut editor, 3), [false, false, false]);
+
+        // Several edits before the rows are shown again.
+        editor.set_cursor(0, 3);
+        assert!(editor.process_keypress(backspace));
+        type_text(&mut editor, "*\n");
+        assert_eq!(in_comment(&mut editor, 4), [false, true, false, false]);
+        editor.set_cursor(1, 0);
+        assert!(editor.process_keypress(backspace));
+        editor.set_cursor(0, 3);
+        assert!(editor.process_keypress(backspace));
+        assert_eq!(in_comment(&mut editor, 3), [false, false, false]);
+        assert_eq!(editor.rows.iter().collect::<Vec<_>>(), ["a/bc*/", "d", "e"]);
+    }
+
+    #[test]
+    fn random_edits_keep_the_state_of_known_rows() {
+        let keys = [
+            KeyEvent::plain(Key::Char('/')),
+            KeyEvent::plain(Key::Char('*')),
+            KeyEvent::plain(Key::Char('a')),
+            KeyEvent::plain(Key::Char('"')),
+            KeyEvent::plain(Key::Enter),
+            KeyEvent::plain(Key::Backspace),
+            KeyEvent::plain(Key::Delete),
+            KeyEvent::plain(Key::Left),
+            KeyEvent::plain(Key::Right),
+            KeyEvent::plain(Key::Up),
+            KeyEvent::plain(Key::Down),
+            KeyEvent::new(Key::Up, Modifiers::SHIFT),
+            KeyEvent::new(Key::End, Modifiers::CTRL),
+            KeyEvent::ctrl('x'),
+            KeyEvent::ctrl('v'),
+            KeyEvent::ctrl('z'),
+            KeyEvent::ctrl('y'),
+        ];
+        for seed in 1..50 {
+            let mut rng = Rng::new(seed);
+            let mut editor = c_editor();
+            for _ in 0..300 {
+                assert!(editor.process_keypress(*rng.pick(&keys)));
+                let expected = expected_in_comment(&editor);
+                let highlighter = &editor.highlighter;
+                assert_eq!(highlighter.in_comment.len(), expected.len());
+                assert!(highlighter.known <= expected.len());
+                assert_eq!(
+                    highlighter.in_comment[..highlighter.known],
+                    expected[..highlighter.known]
+                );
+                // Sometimes only some of the rows are shown.
+                if rng.below(3) == 0 {
+                    let end = rng.below(expected.len() + 1);
+                    assert_eq!(in_comment(&mut editor, end), expected[..end]);
+                }
+            }
+        }
+    }
+}
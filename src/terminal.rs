@@ -0,0 +1,404 @@
+use std::{
+    io::{
+        self,
+        Write,
+    },
+    mem::{
+        self,
+        MaybeUninit,
+    },
+    panic,
+    process,
+    ptr,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicI32,
+            Ordering,
+        },
+        Once,
+        OnceLock,
+    },
+};
+
+#[cfg(target_os = "linux")]
+use libc::__errno_location as errno_location;
+#[cfg(target_os = "macos")]
+use libc::__error as errno_location;
+use libc::{
+    c_int,
+    c_void,
+    sigaction,
+    siginfo_t,
+    termios,
+};
+
+use crate::{
+    ansi,
+    lazy_load,
+};
+
+/// Signals that are handled by the main loop: the terminal was resized, or
+/// the editor should save unsaved changes for recovery and exit. Their handler
+/// only writes them to a pipe, as hardly anything else can safely be done in
+/// a signal handler.
+const HANDLED_SIGNALS: [c_int; 3] = [libc::SIGWINCH, libc::SIGHUP, libc::SIGTERM];
+
+/// Signals that can only be sent from the keyboard while another program runs
+/// in the terminal, e. g. a pager. They are meant for that program only.
+const KEYBOARD_SIGNALS: [c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+/// Signals that terminate the program because of a bug. Their handler restores
+/// the terminal and then lets the action that was set up before (the default
+/// one or that of the Rust runtime) take place. `SIGBUS` is not fatal if it is
+/// caused by a mapped file that was truncated (see `lazy_load`).
+const FATAL_SIGNALS: [c_int; 5] = [
+    libc::SIGBUS,
+    libc::SIGSEGV,
+    libc::SIGABRT,
+    libc::SIGFPE,
+    libc::SIGILL,
+];
+
+/// What is written to the terminal to restore it, before it leaves raw mode.
+const RESTORE_SEQUENCES: [&str; 4] = [
+    ansi::RESET,
+    ansi::SHOW_CURSOR,
+    ansi::DISABLE_BRACKETED_PASTE,
+    ansi::PRIMARY_BUFFER,
+];
+
+/// Written to the signal pipe by `wake_up`, no signal has this number.
+const WAKE_UP: u8 = 0;
+
+static INSTALL_HANDLERS: Once = Once::new();
+/// The settings of the terminal before the editor first entered it.
+static ORIGINAL_TERMIOS: OnceLock<termios> = OnceLock::new();
+/// The actions of the `FATAL_SIGNALS` before their handler was installed.
+static PREVIOUS_ACTIONS: OnceLock<Vec<(c_int, sigaction)>> = OnceLock::new();
+static FD: AtomicI32 = AtomicI32::new(-1);
+/// Whether the terminal is set up for the editor and needs to be restored.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+/// The ends of the pipe that signals are written to, or `-1` before it was
+/// created.
+static SIGNAL_PIPE_READ: AtomicI32 = AtomicI32::new(-1);
+static SIGNAL_PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/// Owns the terminal while the editor runs: it is switched to raw mode and
+/// the alternate screen until the guard is dropped. The same happens when the
+/// program panics, crashes or exits in any other way, so that error messages
+/// end up on the normal screen.
+pub struct Terminal {
+    _private: (),
+}
+
+impl Terminal {
+    pub fn enter(fd: c_int) -> io::Result<Terminal> {
+        if unsafe { libc::isatty(fd) } == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
+        let mut original = MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(fd, original.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        ORIGINAL_TERMIOS.get_or_init(|| unsafe { original.assume_init() });
+        FD.store(fd, Ordering::SeqCst);
+        INSTALL_HANDLERS.call_once(install_handlers);
+        catch_signals()?;
+
+        enter_raw_mode()?;
+        ACTIVE.store(true, Ordering::SeqCst);
+        let mut stdout = io::stdout();
+        write!(
+            stdout,
+            "{}{}",
+            ansi::ALTERNATIVE_BUFFER,
+            ansi::ENABLE_BRACKETED_PASTE
+        )?;
+        stdout.flush()?;
+        Ok(Terminal { _private: () })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Tells whether the terminal is set up for the editor, so that the screen
+/// can be drawn.
+pub(crate) fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// The end of the pipe that signals can be read from, which becomes readable
+/// when a signal arrived.
+pub(crate) fn signal_fd() -> c_int {
+    SIGNAL_PIPE_READ.load(Ordering::SeqCst)
+}
+
+/// Makes the main loop wake up from another thread, like a signal that is
+/// not reported by `take_signals`.
+pub(crate) fn wake_up() {
+    let byte = WAKE_UP;
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE_WRITE.load(Ordering::SeqCst),
+            &byte as *const u8 as _,
+            1,
+        );
+    }
+}
+
+/// Returns the signals that were received since the last call.
+pub(crate) fn take_signals() -> io::Result<Vec<c_int>> {
+    let mut signals = Vec::new();
+    let mut buffer = [0_u8; 64];
+    loop {
+        match unsafe { libc::read(signal_fd(), buffer.as_mut_ptr() as _, buffer.len()) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(signals),
+                    io::ErrorKind::Interrupted => (),
+                    _ => return Err(err),
+                }
+            }
+            0 => return Ok(signals),
+            len => signals.extend(
+                buffer[..len as usize]
+                    .iter()
+                    .filter(|&&byte| byte != WAKE_UP)
+                    .map(|&byte| c_int::from(byte)),
+            ),
+        }
+    }
+}
+
+/// Terminates the process because of `signal` after the terminal was
+/// restored, so that the parent sees the signal as the cause.
+pub fn exit_by_signal(signal: c_int) -> ! {
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    process::exit(128 + signal)
+}
+
+/// Gives the terminal back to the user to run another program in it.
+pub(crate) fn suspend() -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "{}", ansi::DISABLE_BRACKETED_PASTE)?;
+    stdout.flush()?;
+    leave_raw_mode();
+    Ok(())
+}
+
+/// Takes the terminal back after `suspend`.
+pub(crate) fn resume() -> io::Result<()> {
+    enter_raw_mode()?;
+    let mut stdout = io::stdout();
+    write!(stdout, "{}", ansi::ENABLE_BRACKETED_PASTE)?;
+    stdout.flush()
+}
+
+fn install_handlers() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        default_hook(info);
+    }));
+    unsafe {
+        libc::atexit(restore_at_exit);
+    }
+}
+
+/// Installs the signal handlers unless that was done already. Signals are
+/// then reported by `take_signals`.
+pub(crate) fn catch_signals() -> io::Result<()> {
+    if SIGNAL_PIPE_READ.load(Ordering::SeqCst) == -1 {
+        install_signal_handlers()?;
+    }
+    Ok(())
+}
+
+fn install_signal_handlers() -> io::Result<()> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        // Neither end must block, a full pipe means that enough signals are
+        // pending already. Other programs that are run must not inherit them.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    SIGNAL_PIPE_READ.store(fds[0], Ordering::SeqCst);
+    SIGNAL_PIPE_WRITE.store(fds[1], Ordering::SeqCst);
+
+    let handlers = HANDLED_SIGNALS
+        .iter()
+        .map(|&signal| (signal, handle_signal as extern "C" fn(c_int)))
+        .chain(
+            KEYBOARD_SIGNALS
+                .iter()
+                .map(|&signal| (signal, ignore_signal as extern "C" fn(c_int))),
+        );
+    for (signal, handler) in handlers {
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // The previous actions have to be known before the first fatal signal
+    // can arrive.
+    let mut previous_actions = Vec::new();
+    for &signal in &FATAL_SIGNALS {
+        let mut action = MaybeUninit::uninit();
+        if unsafe { libc::sigaction(signal, ptr::null(), action.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        previous_actions.push((signal, unsafe { action.assume_init() }));
+    }
+    PREVIOUS_ACTIONS.set(previous_actions).ok();
+    for &signal in &FATAL_SIGNALS {
+        let mut action: sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handle_fatal_signal
+            as extern "C" fn(c_int, *mut siginfo_t, *mut c_void)
+            as libc::sighandler_t;
+        // The handler has to run on the alternate stack that the Rust runtime
+        // sets up, or it would not be able to restore the terminal after a
+        // stack overflow.
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handle_signal(signal: c_int) {
+    let byte = signal as u8;
+    unsafe {
+        // `write` may change `errno`, which must not be seen by the code that
+        // was interrupted.
+        let errno = *errno_location();
+        libc::write(
+            SIGNAL_PIPE_WRITE.load(Ordering::SeqCst),
+            &byte as *const u8 as _,
+            1,
+        );
+        *errno_location() = errno;
+    }
+}
+
+extern "C" fn handle_fatal_signal(signal: c_int, info: *mut siginfo_t, _: *mut c_void) {
+    // A mapped file that was truncated is not fatal, the main loop finds out
+    // about it like about other signals.
+    if signal == libc::SIGBUS {
+        let errno = unsafe { *errno_location() };
+        let address = unsafe { (*info).si_addr() } as usize;
+        let recovered = lazy_load::replace_missing_pages(address);
+        unsafe {
+            *errno_location() = errno;
+        }
+        if recovered {
+            handle_signal(signal);
+            return;
+        }
+    }
+    restore_in_signal_handler();
+    // A fault happens again when the handler returns, which then triggers the
+    // previous action. A signal that was sent (e. g. by `abort`) has to be sent
+    // again, and only the default action is sure to terminate the program.
+    // It is delivered when the handler returns.
+    if unsafe { (*info).si_code } <= 0 {
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+        return;
+    }
+    let previous_action = PREVIOUS_ACTIONS
+        .get()
+        .and_then(|actions| actions.iter().find(|&&(other, _)| other == signal));
+    if let Some((_, action)) = previous_action {
+        unsafe {
+            libc::sigaction(signal, action, ptr::null_mut());
+        }
+    }
+}
+
+/// Unlike `SIG_IGN`, a handler is reset when another program is executed.
+extern "C" fn ignore_signal(_: c_int) {}
+
+extern "C" fn restore_at_exit() {
+    restore();
+}
+
+/// Puts the terminal back into the state it was in before `Terminal::enter`.
+/// Does nothing if that already happened.
+fn restore() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    // Errors are ignored, there is nothing left to do about them (after SIGHUP
+    // the terminal is usually gone).
+    let mut stdout = io::stdout();
+    for sequence in &RESTORE_SEQUENCES {
+        write!(stdout, "{}", sequence).ok();
+    }
+    stdout.flush().ok();
+    leave_raw_mode();
+}
+
+/// Like `restore`, but only calls functions that can be used in a signal
+/// handler. Standard output is written to directly, which is fine as the
+/// editor flushes it after every screen it draws.
+fn restore_in_signal_handler() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    for sequence in &RESTORE_SEQUENCES {
+        unsafe {
+            libc::write(libc::STDOUT_FILENO, sequence.as_ptr() as _, sequence.len());
+        }
+    }
+    leave_raw_mode();
+}
+
+/// Raw mode: no echo, no line editing, no signals from the keyboard and no
+/// output processing. Reads time out after 100 ms, which only matters when the
+/// terminal is asked for its size; other input is waited for with `poll`.
+fn enter_raw_mode() -> io::Result<()> {
+    let mut raw = *ORIGINAL_TERMIOS
+        .get()
+        .expect("the terminal was not entered");
+    raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+    raw.c_oflag &= !libc::OPOST;
+    raw.c_cflag |= libc::CS8;
+    raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+    raw.c_cc[libc::VMIN] = 0;
+    raw.c_cc[libc::VTIME] = 1;
+    if unsafe { libc::tcsetattr(FD.load(Ordering::SeqCst), libc::TCSAFLUSH, &raw) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    RAW_MODE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+fn leave_raw_mode() {
+    if !RAW_MODE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(original) = ORIGINAL_TERMIOS.get() {
+        unsafe {
+            libc::tcsetattr(FD.load(Ordering::SeqCst), libc::TCSAFLUSH, original);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Helpers shared by the tests of several modules.
+
+use std::{
+    env,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+        MutexGuard,
+        Once,
+    },
+};
+
+/// Tests that map files take this lock, as only a few files can be mapped at
+/// the same time (see `lazy_load::MAPPED_RANGES`).
+static MAPPING_LOCK: Mutex<()> = Mutex::new(());
+
+pub(crate) fn lock_mappings() -> MutexGuard<'static, ()> {
+    // A test that failed while holding the lock does not affect the others.
+    MAPPING_LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Makes `wcwidth` know the widths of non-ASCII characters, which `main` does
+/// with the user's locale.
+pub(crate) fn use_utf8_locale() {
+    static SET_LOCALE: Once = Once::new();
+    SET_LOCALE.call_once(|| unsafe {
+        let locale = libc::setlocale(libc::LC_CTYPE, "C.UTF-8\0".as_ptr() as _);
+        assert!(!locale.is_null(), "The C.UTF-8 locale is not available");
+    });
+}
+
+/// A xorshift generator, so that random tests are repeatable without
+/// depending on another crate.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0..n`, which must not be empty.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// A directory that is removed with its contents when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "kiro-test-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
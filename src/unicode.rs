@@ -0,0 +1,190 @@
+use libc::c_int;
+
+use crate::raw_bytes;
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+#[link(name = "c")]
+extern "C" {
+    fn wcwidth(c: libc::wchar_t) -> c_int;
+}
+
+/// An iterator over the grapheme clusters of a string and their byte
+/// offsets.
+pub(crate) struct Graphemes<'a> {
+    s: &'a str,
+    offset: usize,
+}
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.offset;
+        let mut chars = self.s[start..].chars();
+        let first = chars.next()?;
+        let mut end = start + first.len_utf8();
+        let mut previous = first;
+        let mut regional_indicators = usize::from(is_regional_indicator(first));
+        // Escaped bytes are displayed on their own.
+        let chars = chars.take_while(|_| raw_bytes::escaped_byte(first).is_none());
+        for c in chars {
+            let joins = is_extending(c)
+                || previous == ZERO_WIDTH_JOINER
+                || (is_regional_indicator(c) && regional_indicators == 1);
+            if !joins {
+                break;
+            }
+            regional_indicators += usize::from(is_regional_indicator(c));
+            end += c.len_utf8();
+            previous = c;
+        }
+        self.offset = end;
+        Some((start, &self.s[start..end]))
+    }
+}
+
+pub(crate) fn graphemes(s: &str) -> Graphemes {
+    Graphemes { s, offset: 0 }
+}
+
+/// A grapheme cluster together with its position on screen.
+pub(crate) struct Cell<'a> {
+    pub(crate) offset: usize,
+    pub(crate) column: usize,
+    pub(crate) width: usize,
+    pub(crate) grapheme: &'a str,
+}
+
+/// Lays out the grapheme clusters of `s` on screen, expanding tabs to the
+/// next multiple of `tab_width` columns.
+pub(crate) fn cells(s: &str, tab_width: usize) -> impl Iterator<Item = Cell> {
+    graphemes(s).scan(0, move |column, (offset, grapheme)| {
+        let width = if grapheme == "\t" {
+            tab_width - *column % tab_width
+        }
+        else {
+            grapheme_width(grapheme)
+        };
+        let cell = Cell { offset, column: *column, width, grapheme };
+        *column += width;
+        Some(cell)
+    })
+}
+
+/// Returns the number of columns the grapheme cluster `g` occupies on
+/// screen.
+pub(crate) fn grapheme_width(g: &str) -> usize {
+    let mut chars = g.chars();
+    let first = chars.next().unwrap_or(' ');
+    if let Some(byte) = raw_bytes::escaped_byte(first) {
+        return raw_bytes::escape(byte).len();
+    }
+    if is_regional_indicator(first) || chars.any(|c| c == ZERO_WIDTH_JOINER) {
+        return 2;
+    }
+    char_width(first).unwrap_or(1)
+}
+
+/// Returns the byte offset of the grapheme cluster following the one at
+/// `idx`, or `s.len()` if there is none.
+pub(crate) fn next_boundary(s: &str, idx: usize) -> usize {
+    graphemes(&s[idx..])
+        .nth(1)
+        .map_or_else(|| s.len(), |(offset, _)| idx + offset)
+}
+
+/// Returns the byte offset of the grapheme cluster preceding position `idx`,
+/// or `0` if there is none.
+pub(crate) fn previous_boundary(s: &str, idx: usize) -> usize {
+    graphemes(&s[..idx]).last().map_or(0, |(offset, _)| offset)
+}
+
+/// Returns the byte offset of the grapheme cluster that position `idx` is
+/// part of, which is `idx` itself if it is a boundary.
+pub(crate) fn boundary_at_or_before(s: &str, idx: usize) -> usize {
+    graphemes(s)
+        .map(|(offset, _)| offset)
+        .take_while(|&offset| offset <= idx)
+        .last()
+        .unwrap_or(0)
+}
+
+/// Returns the byte offset of the start of the word before position `idx`,
+/// or `0` if there is none.
+pub(crate) fn previous_word_start(s: &str, idx: usize) -> usize {
+    let mut start = idx;
+    let mut in_word = false;
+    for (offset, grapheme) in graphemes(&s[..idx]).collect::<Vec<_>>().into_iter().rev() {
+        if is_word(grapheme) {
+            in_word = true;
+        }
+        else if in_word {
+            break;
+        }
+        start = offset;
+    }
+    start
+}
+
+/// Returns the byte offset of the end of the word after position `idx`, or
+/// `s.len()` if there is none.
+pub(crate) fn next_word_end(s: &str, idx: usize) -> usize {
+    let mut in_word = false;
+    for (offset, grapheme) in graphemes(&s[idx..]) {
+        if is_word(grapheme) {
+            in_word = true;
+        }
+        else if in_word {
+            return idx + offset;
+        }
+    }
+    s.len()
+}
+
+/// Returns the display column of byte offset `idx` in `s`.
+pub(crate) fn display_column(s: &str, idx: usize, tab_width: usize) -> usize {
+    cells(&s[..idx], tab_width)
+        .last()
+        .map_or(0, |cell| cell.column + cell.width)
+}
+
+/// Returns the byte offset of the grapheme cluster that is displayed at
+/// column `column`, or `s.len()` if `s` is shorter than that.
+pub(crate) fn offset_at_column(s: &str, column: usize, tab_width: usize) -> usize {
+    cells(s, tab_width)
+        .find(|cell| cell.column + cell.width > column)
+        .map_or_else(|| s.len(), |cell| cell.offset)
+}
+
+pub(crate) fn char_width(c: char) -> Option<usize> {
+    let len = unsafe { wcwidth(c as _) };
+    if len < 0 {
+        None
+    }
+    else {
+        Some(len as usize)
+    }
+}
+
+pub(crate) fn render_width(s: &str) -> Option<usize> {
+    s.chars()
+        .map(char_width)
+        .fold(Some(0), |acc, maybe_len| Some(acc? + maybe_len?))
+}
+
+fn is_word(grapheme: &str) -> bool {
+    grapheme
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_alphanumeric() || c == '_')
+}
+
+fn is_extending(c: char) -> bool {
+    let is_emoji_modifier = ('\u{1F3FB}'..='\u{1F3FF}').contains(&c);
+    c == ZERO_WIDTH_JOINER || is_emoji_modifier || (c != '\0' && char_width(c) == Some(0))
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}